@@ -0,0 +1,7 @@
+-- Entries left out of the backup, with why
+create table if not exists skipped
+(
+    path   blob not null,
+    reason text not null,
+    time   text not null
+);
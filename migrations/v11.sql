@@ -0,0 +1,62 @@
+-- Several source roots per backup set. Entries are keyed by their root and their path
+-- relative to it; older sets have a single root, 0.
+create table if not exists root
+(
+    id   integer primary key,
+    path blob not null
+);
+
+create table index_v11
+(
+    path     blob,
+    size     integer,
+    mtime    integer,
+    hash     blob,
+    mode     integer,
+    uid      integer,
+    gid      integer,
+    atime    integer,
+    ctime    integer,
+    btime    integer,
+    dev      integer,
+    ino      integer,
+    xattr_id integer,
+    root_id  integer not null default 0,
+    unique (root_id, path)
+);
+
+insert into index_v11 (path, size, mtime, hash, mode, uid, gid, atime, ctime, btime, dev, ino, xattr_id)
+select path, size, mtime, hash, mode, uid, gid, atime, ctime, btime, dev, ino, xattr_id
+from `index`;
+
+drop table `index`;
+alter table index_v11 rename to `index`;
+create index if not exists index_hash on `index` (hash);
+create index if not exists index_inode on `index` (dev, ino);
+
+create table node_v11
+(
+    path        blob,
+    kind        text,
+    mtime       integer,
+    mode        integer,
+    uid         integer,
+    gid         integer,
+    atime       integer,
+    ctime       integer,
+    btime       integer,
+    link_target blob,
+    xattr_id    integer,
+    rdev        integer,
+    root_id     integer not null default 0,
+    unique (root_id, path)
+);
+
+insert into node_v11 (path, kind, mtime, mode, uid, gid, atime, ctime, btime, link_target, xattr_id, rdev)
+select path, kind, mtime, mode, uid, gid, atime, ctime, btime, link_target, xattr_id, rdev
+from node;
+
+drop table node;
+alter table node_v11 rename to node;
+
+alter table skipped add column root_id integer not null default 0;
@@ -0,0 +1,4 @@
+-- Holes inside chunks of sparse files, as encoded extents relative to the chunk; only the
+-- data between them is stored. `hole` still marks a chunk lying entirely in a hole, which
+-- older sets record without extents.
+alter table chunk add column holes blob;
@@ -0,0 +1,5 @@
+create table if not exists meta
+(
+    key   text primary key,
+    value text
+);
@@ -0,0 +1,24 @@
+create index if not exists index_hash on `index` (hash);
+
+-- Chunks are keyed by their file and position in it. The position was implied by the insertion
+-- order before; a file stored twice in one generation only keeps its first copy.
+create table chunk_v3
+(
+    file_hash  blob    not null,
+    chunk_n    integer not null,
+    chunk_hash blob,
+    bak_n      integer,
+    offset     integer,
+    size       integer,
+    primary key (file_hash, chunk_n)
+);
+
+insert or ignore into chunk_v3 (file_hash, chunk_n, chunk_hash, bak_n, offset, size)
+select file_hash, row_number() over (partition by file_hash order by start) - 1, chunk_hash, bak_n, offset, size
+from (select *,
+             sum(size) over (partition by file_hash order by rowid rows unbounded preceding) - size as start
+      from chunk) as c
+where start < coalesce((select size from `index` as i where i.hash = c.file_hash limit 1), start + 1);
+
+drop table chunk;
+alter table chunk_v3 rename to chunk;
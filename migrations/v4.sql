@@ -0,0 +1,6 @@
+alter table `index` add column mode integer;
+alter table `index` add column uid integer;
+alter table `index` add column gid integer;
+alter table `index` add column atime integer;
+alter table `index` add column ctime integer;
+alter table `index` add column btime integer;
@@ -0,0 +1,14 @@
+-- Entries without content, such as symlinks
+create table if not exists node
+(
+    path        blob unique,
+    kind        text,
+    mtime       integer,
+    mode        integer,
+    uid         integer,
+    gid         integer,
+    atime       integer,
+    ctime       integer,
+    btime       integer,
+    link_target blob
+);
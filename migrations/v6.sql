@@ -0,0 +1,5 @@
+-- Device and inode numbers, only for files with more than one hard link
+alter table `index` add column dev integer;
+alter table `index` add column ino integer;
+
+create index if not exists index_inode on `index` (dev, ino);
@@ -0,0 +1,10 @@
+-- Extended attribute sets, shared by all entries having identical ones
+create table if not exists xattr
+(
+    id   integer primary key,
+    hash blob unique,
+    data blob
+);
+
+alter table `index` add column xattr_id integer;
+alter table node add column xattr_id integer;
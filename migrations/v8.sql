@@ -0,0 +1,2 @@
+-- Chunks in holes of sparse files, stored without data
+alter table chunk add column hole integer not null default 0;
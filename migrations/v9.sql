@@ -0,0 +1,2 @@
+-- Device numbers of device nodes
+alter table node add column rdev integer;
@@ -0,0 +1,861 @@
+//! Running backups.
+//!
+//! An initial backup stores every distinct file content; a differential one is based on an
+//! earlier index and only stores contents that index doesn't know.
+
+use crate::catalog::write_catalog;
+use crate::db::{IndexDb, IndexDbTx, IndexMeta, IndexRow};
+use crate::sparse::{chunk_holes, data_extents, stored_size};
+use crate::volume::FrameHeader;
+use crate::{
+    chunks_ranges, compute_file_hash, index_files, index_formatted_name, index_pick_last,
+    legacy_user_dir, BakOutputWriter, ChunkInfo, FileEntry, Hash, HashReadWrapper, IndexOptions,
+    InodeId, Range, SkippedEntry, SourceIndex, SplitInfo, HASH_ALGORITHM,
+};
+use anyhow::anyhow;
+use chrono::Local;
+use log::{error, info};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use yeet_ops::yeet;
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 128 * 1024 * 1024;
+pub const DEFAULT_BACKUP_SIZE: u64 = 3 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    /// Source directories, each one a root of the backup set
+    pub source_dirs: Vec<PathBuf>,
+    /// Directory for the 'bak' volumes and a copy of the index; created if missing, and must
+    /// be empty
+    pub out_dir: PathBuf,
+    /// Directory the new index database is kept in
+    pub state_dir: PathBuf,
+    /// Index to base a differential backup on
+    pub base_index: BaseIndex,
+    /// Size of each chunk files are split into
+    pub chunk_size: u64,
+    /// Size limit of each 'bak' volume; not less than `chunk_size`
+    pub backup_size: u64,
+    /// External program the 'bak' volumes are piped through
+    pub output_filter: Option<Vec<OsString>>,
+    pub index_options: IndexOptions,
+    /// Also write the `index.txt` and `diff.txt` catalog into the output directory
+    pub write_catalog: bool,
+}
+
+impl BackupConfig {
+    /// A configuration with default sizes and options.
+    pub fn new(source_dirs: Vec<PathBuf>, out_dir: PathBuf, state_dir: PathBuf) -> Self {
+        Self {
+            source_dirs,
+            out_dir,
+            state_dir,
+            base_index: BaseIndex::Auto,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            backup_size: DEFAULT_BACKUP_SIZE,
+            output_filter: None,
+            index_options: Default::default(),
+            write_catalog: false,
+        }
+    }
+}
+
+/// Which index a backup is based on.
+#[derive(Default, Debug, Clone)]
+pub enum BaseIndex {
+    /// The latest generation of the state directory or, failing that for a single source
+    /// directory, of the in-source directory kept by older versions; an initial backup if
+    /// there is none
+    #[default]
+    Auto,
+    /// An initial backup, storing every distinct content
+    Initial,
+    Path(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct BackupReport {
+    /// The new index database in the state directory
+    pub index_db: PathBuf,
+    pub differential: bool,
+    /// Files in the new index
+    pub file_count: usize,
+    /// Files whose content is stored in this backup set
+    pub stored_file_count: usize,
+    /// Entries left out; the backup is incomplete if not empty
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// What a backup would store, from [`BackupJob::plan`].
+#[derive(Debug)]
+pub struct BackupPlan {
+    pub differential: bool,
+    /// Whether contents were hashed to leave out those already stored. Otherwise files are
+    /// only matched by path, size and mtime, and the plan is an upper bound.
+    pub hashed: bool,
+    /// Files the new index would have
+    pub file_count: usize,
+    /// Files whose content would be stored, sorted by path
+    pub stored_files: Vec<FileEntry>,
+    /// Bytes of file data to store; holes take none
+    pub stored_bytes: u64,
+    /// Total size of the 'bak' volumes, before any output filter
+    pub volume_bytes: u64,
+    pub volume_count: usize,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// Assigns frames to 'bak' volumes of at most `backup_size` bytes; a frame never spans two
+/// volumes, and one larger than `backup_size` gets a volume of its own.
+#[derive(Default)]
+struct VolumeLayout {
+    bak_n: i32,
+    bak_total_size: u64,
+}
+
+impl VolumeLayout {
+    /// Places a frame, returning whether it starts a new volume.
+    fn place(&mut self, frame_len: u64, backup_size: u64) -> bool {
+        let new_volume = self.bak_total_size != 0 && self.bak_total_size + frame_len > backup_size;
+        if new_volume {
+            self.bak_n += 1;
+            self.bak_total_size = 0;
+        }
+        self.bak_total_size += frame_len;
+        new_volume
+    }
+}
+
+/// One backup run. Holds no global state, so several jobs can run in one process; jobs
+/// sharing a state directory must start at least a second apart.
+pub struct BackupJob {
+    config: BackupConfig,
+    /// The resolved [`BackupConfig::base_index`]
+    base_index: Option<PathBuf>,
+    /// Canonical paths of the source roots, by root id
+    roots: Vec<PathBuf>,
+}
+
+impl BackupJob {
+    pub fn new(config: BackupConfig) -> anyhow::Result<Self> {
+        if config.source_dirs.is_empty() {
+            yeet!(anyhow!("No source directory"));
+        }
+        if config.chunk_size == 0 {
+            yeet!(anyhow!("Chunk size must not be zero"));
+        }
+        if config.backup_size < config.chunk_size {
+            yeet!(anyhow!("Backup size must not be less than the chunk size"));
+        }
+        let base_index = match &config.base_index {
+            BaseIndex::Auto => Self::pick_base_index(&config)?,
+            BaseIndex::Initial => None,
+            BaseIndex::Path(x) if !x.is_file() => {
+                yeet!(anyhow!("Base index not found: {}", x.display()))
+            }
+            BaseIndex::Path(x) => Some(x.clone()),
+        };
+        let roots = config
+            .source_dirs
+            .iter()
+            .map(fs::canonicalize)
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            config,
+            base_index,
+            roots,
+        })
+    }
+
+    fn pick_base_index(config: &BackupConfig) -> anyhow::Result<Option<PathBuf>> {
+        if config.state_dir.is_dir() {
+            if let Some(x) = index_pick_last(&config.state_dir)? {
+                return Ok(Some(x));
+            }
+        }
+        // carry on the chain kept in the source by older versions
+        if let [root] = &config.source_dirs[..] {
+            if let Some(legacy) = legacy_user_dir(root) {
+                return index_pick_last(legacy);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn config(&self) -> &BackupConfig {
+        &self.config
+    }
+
+    /// Runs the backup, writing the volumes into the output directory and the new index
+    /// into both the state directory and the output directory.
+    pub fn run(&self) -> anyhow::Result<BackupReport> {
+        let out_dir = &self.config.out_dir;
+        if out_dir.exists() && fs::read_dir(out_dir)?.count() != 0 {
+            yeet!(anyhow!(
+                "Non-empty output directory; please choose another one."
+            ));
+        }
+        fs::create_dir_all(out_dir)?;
+
+        fs::create_dir_all(&self.config.state_dir)?;
+        let index_db = self.config.state_dir.join(index_formatted_name());
+        // claims the name, which only has a resolution of one second, so a run can't replace
+        // the generation of another one, possibly its own base
+        match File::create_new(&index_db) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => yeet!(anyhow!(
+                "Generation {} already exists; please retry in a second",
+                index_db.display()
+            )),
+            Err(e) => yeet!(anyhow::Error::from(e)),
+        }
+        let report = match &self.base_index {
+            None => {
+                info!("Processing initial backup...");
+                self.initial_backup(&index_db)
+            }
+            Some(x) => {
+                info!("Processing differential backup...");
+                self.differential_backup(&index_db, x)
+            }
+        }
+        .inspect_err(|_| {
+            // an incomplete index must not become the base of the next run
+            let _ = fs::remove_file(&index_db);
+        })?;
+        fs::copy(&index_db, out_dir.join("index.db"))?;
+        if self.config.write_catalog {
+            write_catalog(&IndexDb::open_read_only(&index_db)?, out_dir)?;
+        }
+        Ok(report)
+    }
+
+    /// Works out what [`run`](Self::run) would store, without writing anything. `hash` also
+    /// hashes the files not matched by metadata, as a real run does, for an exact plan.
+    pub fn plan(&self, hash: bool) -> anyhow::Result<BackupPlan> {
+        info!("Indexing files...");
+        let SourceIndex {
+            files, mut skipped, ..
+        } = self.index_source()?;
+        let skipped_before_hashing = skipped.len();
+        info!("File count: {}", files.len());
+
+        let mut known_hashes = HashSet::new();
+        let remaining = match &self.base_index {
+            Some(ref_db_path) => {
+                let ref_db = IndexDb::open_read_only(ref_db_path)?;
+                let root_map = self.map_ref_roots(&ref_db)?;
+                let old_index = ref_db.select_index_all()?;
+                let metadata_set = old_index
+                    .iter()
+                    .filter_map(|x| {
+                        let root = *root_map.get(&x.entry.root)?;
+                        Some((root, x.entry.path.as_path(), x.entry.mtime, x.entry.size))
+                    })
+                    .collect::<HashSet<_>>();
+                known_hashes.extend(old_index.iter().map(|x| Hash(x.hash)));
+                files
+                    .iter()
+                    .filter(|e| {
+                        !metadata_set.contains(&(e.root, e.path.as_path(), e.mtime, e.size))
+                    })
+                    .collect::<Vec<_>>()
+            }
+            None => files.iter().collect(),
+        };
+
+        let mut stored_files = Vec::new();
+        if hash {
+            let mut hard_link_hashes = HashMap::new();
+            let remaining_count = remaining.len();
+            for (i, e) in remaining.into_iter().enumerate() {
+                info!("Hashing: [{}/{}] {}", i, remaining_count, e.path.display());
+                let Some(file_hash) = self.hash_or_skip(e, &mut hard_link_hashes, &mut skipped)?
+                else {
+                    continue;
+                };
+                if known_hashes.insert(file_hash) {
+                    stored_files.push(e.clone());
+                }
+            }
+        } else {
+            // hard links share their content even unhashed
+            let mut inodes = HashSet::new();
+            stored_files.extend(
+                remaining
+                    .into_iter()
+                    .filter(|e| e.hard_link.is_none_or(|x| inodes.insert(x)))
+                    .cloned(),
+            );
+        }
+        stored_files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut layout = VolumeLayout::default();
+        let mut stored_bytes = 0;
+        let mut volume_bytes = 0;
+        for e in &stored_files {
+            let chunks = chunks_ranges(e.size, self.config.chunk_size);
+            let mut holes = match File::open(e.full_path(&self.roots)) {
+                Ok(f) => chunk_holes(&f, &chunks)?,
+                Err(_) => vec![Vec::new(); chunks.len()],
+            };
+            for (chunk_n, r) in chunks.iter().enumerate() {
+                let header = FrameHeader {
+                    path: e.path.clone(),
+                    file_size: e.size,
+                    mtime: *e.mtime,
+                    file_hash: Hash::default(),
+                    chunk_n: chunk_n as u32,
+                    chunk_count: chunks.len() as u32,
+                    chunk_size: r.size,
+                    holes: std::mem::take(&mut holes[chunk_n]),
+                    root: e.root,
+                    root_path: Some(self.roots[e.root as usize].clone()),
+                };
+                layout.place(header.frame_len(), self.config.backup_size);
+                volume_bytes += header.frame_len();
+                stored_bytes += header.stored_size();
+            }
+        }
+
+        let file_count = files.len() - (skipped.len() - skipped_before_hashing);
+        Ok(BackupPlan {
+            differential: self.base_index.is_some(),
+            hashed: hash,
+            file_count,
+            stored_files,
+            stored_bytes,
+            volume_bytes,
+            // the first volume is written even if empty
+            volume_count: layout.bak_n as usize + 1,
+            skipped,
+        })
+    }
+
+    fn index_source(&self) -> io::Result<SourceIndex> {
+        let mut index = SourceIndex::default();
+        for (root, dir) in self.config.source_dirs.iter().enumerate() {
+            let mut x = index_files(dir, root as u32, &self.config.index_options)?;
+            index.files.append(&mut x.files);
+            index.nodes.append(&mut x.nodes);
+            index.skipped.append(&mut x.skipped);
+        }
+        info!("Non-regular entry count: {}", index.nodes.len());
+        Ok(index)
+    }
+
+    fn insert_roots(&self, db_tx: &IndexDbTx) -> anyhow::Result<()> {
+        for (id, path) in self.roots.iter().enumerate() {
+            db_tx.insert_root_row(id as u32, path)?;
+        }
+        Ok(())
+    }
+
+    /// Maps root ids of `ref_db` to those of this backup by their paths. A set made before
+    /// roots were recorded has the single root 0.
+    fn map_ref_roots(&self, ref_db: &IndexDb) -> anyhow::Result<HashMap<u32, u32>> {
+        let ref_roots = ref_db.select_roots()?;
+        if ref_roots.is_empty() {
+            return Ok(HashMap::from([(0, 0)]));
+        }
+        Ok(ref_roots
+            .into_iter()
+            .filter_map(|(id, path)| {
+                let new_id = self.roots.iter().position(|x| *x == path)?;
+                Some((id, new_id as u32))
+            })
+            .collect())
+    }
+
+    /// Computes the content hash of a file, only once for all hard links of it.
+    fn hash_file_entry(
+        &self,
+        e: &FileEntry,
+        hard_link_hashes: &mut HashMap<InodeId, Hash>,
+    ) -> io::Result<Hash> {
+        if let Some(hash) = e.hard_link.and_then(|x| hard_link_hashes.get(&x)) {
+            return Ok(*hash);
+        }
+        let hash = compute_file_hash(e.full_path(&self.roots))?;
+        if let Some(x) = e.hard_link {
+            hard_link_hashes.insert(x, hash);
+        }
+        Ok(hash)
+    }
+
+    /// Like [`hash_file_entry`], but a file that can't be read is recorded in `skipped`, unless
+    /// in strict mode.
+    fn hash_or_skip(
+        &self,
+        e: &FileEntry,
+        hard_link_hashes: &mut HashMap<InodeId, Hash>,
+        skipped: &mut Vec<SkippedEntry>,
+    ) -> io::Result<Option<Hash>> {
+        match self.hash_file_entry(e, hard_link_hashes) {
+            Ok(x) => Ok(Some(x)),
+            Err(err) if !self.config.index_options.strict => {
+                error!("Skipped: {}: {err}", e.path.display());
+                skipped.push(SkippedEntry::new(e.root, e.path.clone(), err));
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn new_index_meta(&self) -> IndexMeta {
+        IndexMeta {
+            tool_version: Some(env!("CARGO_PKG_VERSION").into()),
+            created_at: Some(Local::now().to_rfc3339()),
+            chunk_size: Some(self.config.chunk_size),
+            hash_algorithm: Some(HASH_ALGORITHM.into()),
+            filter: self.config.output_filter.as_ref().map(|x| {
+                let args = x.iter().map(|x| x.to_string_lossy()).collect::<Vec<_>>();
+                serde_json::to_string(&args).expect("Unexpected: strings failed to serialize")
+            }),
+            out_dir: Some(
+                fs::canonicalize(&self.config.out_dir).unwrap_or(self.config.out_dir.clone()),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn differential_backup(
+        &self,
+        index_db: &Path,
+        ref_db_path: &Path,
+    ) -> anyhow::Result<BackupReport> {
+        // full scan is still needed
+        info!("Indexing files...");
+        let SourceIndex {
+            files,
+            nodes,
+            mut skipped,
+        } = self.index_source()?;
+        let skipped_before_hashing = skipped.len();
+        info!("File count: {}", files.len());
+        info!("Picked ref_db: {}", ref_db_path.display());
+        let ref_db = IndexDb::open_read_only(ref_db_path)?;
+        let ref_meta = ref_db.read_meta()?;
+        if let Some(x) = &ref_meta.hash_algorithm {
+            if x != HASH_ALGORITHM {
+                yeet!(anyhow!(
+                    "ref_db uses hash algorithm {x}, which is not comparable with {HASH_ALGORITHM}"
+                ));
+            }
+        }
+
+        // find out differential files by path, mtime and size
+        info!("Reading old index database...");
+        let old_index = ref_db.select_index_all()?;
+        let mut duplicates: Vec<(&FileEntry, Hash)> = Vec::new();
+        info!("Deduplicating by metadata...");
+        // files of roots not backed up this time can still match by hash
+        let root_map = self.map_ref_roots(&ref_db)?;
+        let metadata_map = old_index
+            .iter()
+            .filter_map(|x| {
+                let root = *root_map.get(&x.entry.root)?;
+                Some((
+                    (root, x.entry.path.as_path(), x.entry.mtime, x.entry.size),
+                    x,
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+        let old_index_hash_set = old_index.iter().map(|x| &x.hash).collect::<HashSet<_>>();
+        let mut remaining = Vec::new();
+        for e in &files {
+            if let Some(v) = metadata_map.get(&(e.root, e.path.as_path(), e.mtime, e.size)) {
+                duplicates.push((e, Hash(v.hash)));
+            } else {
+                remaining.push(e);
+            }
+        }
+        info!("File count: {}", remaining.len());
+        info!("Deduplicating diff by hash...");
+        // if the diff file hash matches in the old file index, skip its backup
+        let mut files_to_backup = Vec::new();
+        let mut new_hash_set = HashSet::new();
+        let mut hard_link_hashes = HashMap::new();
+        let remaining_count = remaining.len();
+        for (i, e) in remaining.into_iter().enumerate() {
+            info!("Hashing: [{}/{}] {}", i, remaining_count, e.path.display());
+            let Some(file_hash) = self.hash_or_skip(e, &mut hard_link_hashes, &mut skipped)? else {
+                continue;
+            };
+            // also skip the same content appearing more than once in the new files
+            if !old_index_hash_set.contains(&&*file_hash) && new_hash_set.insert(file_hash) {
+                files_to_backup.push((file_hash, e));
+            } else {
+                duplicates.push((e, file_hash));
+            }
+        }
+        files_to_backup.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+        info!("File count: {}", files_to_backup.len());
+        let indexed_count = files.len() - (skipped.len() - skipped_before_hashing);
+        assert_eq!(duplicates.len() + files_to_backup.len(), indexed_count);
+
+        info!("Writing to backup files...");
+        let (file_splits, failed) =
+            self.write_bak_files(files_to_backup.iter().copied(), &mut skipped)?;
+        files_to_backup.retain(|x| !failed.contains(&x.0));
+        let duplicate_count = duplicates.len();
+        let duplicates = drop_unstored(duplicates, &failed, &mut skipped);
+        let indexed_count = indexed_count - failed.len() - (duplicate_count - duplicates.len());
+
+        info!("Creating index database...");
+        let mut db = IndexDb::new(index_db, true)?;
+        db.write_meta(&self.new_index_meta())?;
+        let db_tx = db.transaction()?;
+        self.insert_roots(&db_tx)?;
+        db_tx.insert_file_split_info(&file_splits)?;
+        let new_files_ref_map = files_to_backup
+            .iter()
+            .map(|x| (x.1 as *const _, x))
+            .collect::<HashMap<_, _>>();
+        // the current index = files_to_backup ...
+        for e in &files {
+            let entry = new_files_ref_map.get(&(e as *const _));
+            if let Some(e) = entry {
+                // this is the new file being backed up
+                let row = IndexRow {
+                    hash: *e.0,
+                    entry: e.1.clone(),
+                };
+                db_tx.insert_index_row(&row)?;
+            }
+        }
+        // ... + duplicates
+        for x in duplicates {
+            db_tx.insert_index_row(&IndexRow {
+                entry: x.0.clone(),
+                hash: *x.1,
+            })?;
+        }
+        for x in &nodes {
+            db_tx.insert_node_row(x)?;
+        }
+        for x in &skipped {
+            db_tx.insert_skipped_row(x)?;
+        }
+        // so if I do db_tx.0.commit()? outside, it doesn't work
+        {
+            let tx = db_tx;
+            tx.0.commit()?;
+        }
+        assert_eq!(db.query_index_row_count()?, indexed_count as u64);
+        assert_eq!(
+            db.query_chunk_row_count()?,
+            file_splits.iter().map(|x| x.chunks.len()).sum::<usize>() as u64
+        );
+
+        Ok(BackupReport {
+            index_db: index_db.into(),
+            differential: true,
+            file_count: indexed_count,
+            stored_file_count: files_to_backup.len(),
+            skipped,
+        })
+    }
+
+    fn initial_backup(&self, index_db: &Path) -> anyhow::Result<BackupReport> {
+        // Do the first full backup
+        info!("Indexing files...");
+        let SourceIndex {
+            files,
+            nodes,
+            mut skipped,
+        } = self.index_source()?;
+        let file_count = files.len();
+        info!("File count: {file_count}");
+
+        info!("Deduplicating by hash. Please wait...");
+        let mut file_hash_list = Vec::new();
+        let mut unique_list = HashMap::new();
+        let mut hard_link_hashes = HashMap::new();
+        for (i, e) in files.iter().enumerate() {
+            info!("Hashing: [{}/{}] {}", i, file_count, e.path.display());
+            let Some(hash) = self.hash_or_skip(e, &mut hard_link_hashes, &mut skipped)? else {
+                continue;
+            };
+            unique_list.insert(hash, e);
+            file_hash_list.push((e, hash));
+        }
+        info!("Writing to backup files...");
+
+        let mut files_to_backup = unique_list.iter().map(|x| (*x.0, *x.1)).collect::<Vec<_>>();
+        files_to_backup.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+        let (file_splits, failed) =
+            self.write_bak_files(files_to_backup.iter().copied(), &mut skipped)?;
+        let stored_file_count = files_to_backup.len() - failed.len();
+        let file_hash_list = drop_unstored(file_hash_list, &failed, &mut skipped);
+
+        info!("Creating index database...");
+        let mut db = IndexDb::new(index_db, true)?;
+        db.write_meta(&self.new_index_meta())?;
+        let db_tx = db.transaction()?;
+        self.insert_roots(&db_tx)?;
+        let file_count = file_hash_list.len();
+        for (entry, hash) in file_hash_list {
+            db_tx.insert_index_row(&IndexRow {
+                hash: *hash,
+                entry: entry.clone(),
+            })?;
+        }
+        for x in &nodes {
+            db_tx.insert_node_row(x)?;
+        }
+        for x in &skipped {
+            db_tx.insert_skipped_row(x)?;
+        }
+        db_tx.insert_file_split_info(&file_splits)?;
+        db_tx.0.commit()?;
+        info!("Done");
+        Ok(BackupReport {
+            index_db: index_db.into(),
+            differential: false,
+            file_count,
+            stored_file_count,
+            skipped,
+        })
+    }
+
+    /// Writes the contents of `files` into 'bak' volumes. A file that can't be read in full,
+    /// e.g. one that shrank since it was hashed, is recorded in `skipped` unless in strict
+    /// mode; its hash is returned with the others' splits, and no index row may refer to it.
+    fn write_bak_files<'a>(
+        &self,
+        files: impl ExactSizeIterator<Item = (Hash, &'a FileEntry)>,
+        skipped: &mut Vec<SkippedEntry>,
+    ) -> anyhow::Result<(Vec<SplitInfo>, HashSet<Hash>)> {
+        let file_count = files.len();
+        let mut file_chunks_hash = vec![Vec::<Hash>::new(); file_count];
+
+        let mut layout = VolumeLayout::default();
+        // chunk offset of the current 'bak' file in index.txt
+        let mut chunk_offset = 0_u64;
+        let out_dir = &self.config.out_dir;
+        let output_filter = self.config.output_filter.as_ref();
+        let create_bak_file = |bak_n: i32| -> anyhow::Result<_> {
+            let bak_file = out_dir.join(format!("bak{bak_n}"));
+            let writer = BufWriter::new(File::create(&bak_file)?);
+            let writer = BakOutputWriter::new(writer, output_filter)?;
+            Ok(writer)
+        };
+        let mut bak_output = create_bak_file(layout.bak_n)?;
+
+        let mut split_info_list = Vec::new();
+        let mut failed = HashSet::new();
+
+        for (i, e) in files.into_iter().enumerate() {
+            let file_size = e.1.size;
+            let file_path = e.1.path.as_path();
+            let file_path_full = e.1.full_path(&self.roots);
+            let mut split_info = SplitInfo {
+                file_hash: e.0,
+                chunks: Default::default(),
+            };
+            let mut failure = None;
+
+            let chunks = chunks_ranges(file_size, self.config.chunk_size);
+            let opened = File::open(file_path_full).and_then(|mut file| {
+                let holes = chunk_holes(&file, &chunks)?;
+                file.rewind()?;
+                Ok((file, holes))
+            });
+            // gone or unreadable since it was hashed; nothing is written for it yet
+            let (file, mut holes) = match opened {
+                Ok(x) => x,
+                Err(err) => {
+                    self.skip_unstored(e.1, err, skipped)?;
+                    failed.insert(e.0);
+                    continue;
+                }
+            };
+            let mut reader = BufReader::new(file);
+            for (chunk_n, r) in chunks.iter().enumerate() {
+                info!(
+                    "Write file [{i}/{file_count}] {} chunk #{}",
+                    file_path.display(),
+                    chunk_n + 1
+                );
+                let header = FrameHeader {
+                    path: file_path.into(),
+                    file_size,
+                    mtime: *e.1.mtime,
+                    file_hash: e.0,
+                    chunk_n: chunk_n as u32,
+                    chunk_count: chunks.len() as u32,
+                    chunk_size: r.size,
+                    holes: std::mem::take(&mut holes[chunk_n]),
+                    root: e.1.root,
+                    root_path: Some(self.roots[e.1.root as usize].clone()),
+                };
+                let frame_len = header.frame_len();
+
+                // Check if a new 'bak' file is needed, that's, this 'bak' file is not sufficient for
+                // storing a new chunk.
+                // write to the new 'bak' file; close the old and create a new one
+                if layout.place(frame_len, self.config.backup_size) {
+                    chunk_offset = 0;
+                    bak_output.flush()?;
+                    // directly assign to it; Rust will drop the old one
+                    bak_output = create_bak_file(layout.bak_n)?;
+                }
+
+                header.write_to(&mut bak_output)?;
+                chunk_offset += header.encoded_len();
+                let stored_size = header.stored_size();
+                let data_len = frame_len - header.encoded_len();
+                let copied = copy_chunk(&mut reader, &mut bak_output, r.size, &header.holes)?;
+                if copied.len < stored_size {
+                    // pad to the size in the header so the following frames stay
+                    // readable; the hash of the bytes read won't match, so the chunk
+                    // reads as corrupted
+                    io::copy(
+                        &mut io::repeat(0).take(stored_size - copied.len),
+                        &mut bak_output,
+                    )?;
+                    failure = Some(match copied.read_error {
+                        Some(e) => format!("read failed in chunk #{}: {e}", chunk_n + 1),
+                        None => format!(
+                            "changed while being backed up: ended at {} of {file_size} bytes",
+                            r.start + copied.end
+                        ),
+                    });
+                }
+                bak_output.write_all(&*copied.hash)?;
+                file_chunks_hash[i].push(copied.hash);
+
+                split_info.chunks.push(ChunkInfo {
+                    hash: copied.hash,
+                    bak_n: layout.bak_n,
+                    offset: chunk_offset,
+                    size: r.size,
+                    holes: header.holes,
+                });
+
+                chunk_offset += data_len;
+                if failure.is_some() {
+                    break;
+                }
+            }
+            if failure.is_none() && reader.stream_position()? != file_size {
+                failure = Some("changed while being backed up".into());
+            }
+            match failure {
+                None => split_info_list.push(split_info),
+                Some(reason) => {
+                    self.skip_unstored(e.1, reason, skipped)?;
+                    failed.insert(e.0);
+                }
+            }
+        }
+        // flush the last 'bak' file
+        bak_output.flush()?;
+
+        Ok((split_info_list, failed))
+    }
+
+    /// Records a file whose content couldn't be stored, or fails in strict mode.
+    fn skip_unstored(
+        &self,
+        e: &FileEntry,
+        reason: impl Display,
+        skipped: &mut Vec<SkippedEntry>,
+    ) -> anyhow::Result<()> {
+        if self.config.index_options.strict {
+            yeet!(anyhow!("{}: {reason}", e.path.display()));
+        }
+        error!("Skipped: {}: {reason}", e.path.display());
+        skipped.push(SkippedEntry::new(e.root, e.path.clone(), reason));
+        Ok(())
+    }
+}
+
+/// Leaves out the files whose content failed to be stored, recording those not recorded yet.
+fn drop_unstored<'a>(
+    files: Vec<(&'a FileEntry, Hash)>,
+    failed: &HashSet<Hash>,
+    skipped: &mut Vec<SkippedEntry>,
+) -> Vec<(&'a FileEntry, Hash)> {
+    if failed.is_empty() {
+        return files;
+    }
+    let (dropped, kept): (Vec<_>, Vec<_>) = files.into_iter().partition(|x| failed.contains(&x.1));
+    for (e, _) in dropped {
+        if !skipped.iter().any(|x| x.root == e.root && x.path == e.path) {
+            let reason = "same content as a file that couldn't be stored";
+            error!("Skipped: {}: {reason}", e.path.display());
+            skipped.push(SkippedEntry::new(e.root, e.path.clone(), reason));
+        }
+    }
+    kept
+}
+
+struct CopiedChunk {
+    /// Bytes copied, those of the holes excluded
+    len: u64,
+    /// Offset in the chunk the copy got to
+    end: u64,
+    /// Of the bytes copied
+    hash: Hash,
+    read_error: Option<io::Error>,
+}
+
+/// Copies the data of a chunk of `size` bytes from `reader`, seeking over its `holes`. A
+/// read error ends the copy early like the end of the file does; write errors are returned.
+fn copy_chunk(
+    reader: impl Read + Seek,
+    writer: &mut impl Write,
+    size: u64,
+    holes: &[Range],
+) -> io::Result<CopiedChunk> {
+    let mut reader = HashReadWrapper::new(reader);
+    let mut buf = vec![0_u8; 64 * 1024];
+    let mut len = 0_u64;
+    let mut end = 0_u64;
+    let mut read_error = None;
+    'extents: for x in data_extents(size, holes) {
+        if let Err(e) = reader.seek(SeekFrom::Current((x.start - end) as i64)) {
+            read_error = Some(e);
+            break;
+        }
+        end = x.start;
+        let mut extent = (&mut reader).take(x.size);
+        loop {
+            match extent.read(&mut buf) {
+                Ok(0) if extent.limit() == 0 => break,
+                Ok(0) => break 'extents,
+                Ok(n) => {
+                    writer.write_all(&buf[..n])?;
+                    len += n as u64;
+                    end += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    read_error = Some(e);
+                    break 'extents;
+                }
+            }
+        }
+    }
+    if read_error.is_none() && len == stored_size(size, holes) && end < size {
+        // a trailing hole
+        match reader.seek(SeekFrom::Current((size - end) as i64)) {
+            Ok(_) => end = size,
+            Err(e) => read_error = Some(e),
+        }
+    }
+    Ok(CopiedChunk {
+        len,
+        end,
+        hash: reader.finalize(),
+        read_error,
+    })
+}
@@ -0,0 +1,346 @@
+//! Listings of the files in an index, for people and for other programs.
+//!
+//! A backup can also write a catalog next to its volumes: `index.txt` listing every file of
+//! the generation and `diff.txt` listing those stored in this output directory, both as
+//! `path,size` CSV.
+
+use crate::db::{IndexDb, IndexRow};
+use crate::{FileNanoTime, Hash, PathBytes};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// Tab-separated size, mtime, hash and path
+    #[default]
+    Text,
+    /// An array of objects; non-UTF-8 paths are converted lossily
+    Json,
+    /// With a header row; paths are written as raw bytes
+    Csv,
+}
+
+/// Which files of a generation to export.
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    /// All files
+    #[default]
+    Index,
+    /// Files whose content is stored in this generation's volumes
+    Diff,
+}
+
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `path,size` rows without a header; paths are written as raw bytes
+    #[default]
+    Csv,
+    /// An array of objects, like `list --format json`
+    Json,
+    /// Paths terminated by NUL, e.g. for `xargs -0`
+    Nul,
+}
+
+pub const CATALOG_INDEX_NAME: &str = "index.txt";
+pub const CATALOG_DIFF_NAME: &str = "diff.txt";
+
+#[derive(clap::ValueEnum, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Path,
+    Size,
+    Mtime,
+}
+
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    /// Relative to the source root, or joined to it in sets with several roots
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: FileNanoTime,
+    pub hash: Hash,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    path: &'a str,
+    size: u64,
+    mtime: String,
+    hash: String,
+}
+
+/// Source root paths by id, for displaying entries of sets with several roots.
+pub fn root_paths(db: &IndexDb) -> anyhow::Result<HashMap<u32, PathBuf>> {
+    let roots = db.select_roots()?;
+    if roots.len() <= 1 {
+        // paths relative to the only root are unambiguous
+        return Ok(HashMap::new());
+    }
+    Ok(roots.into_iter().collect())
+}
+
+impl CatalogEntry {
+    pub fn from_row(row: &IndexRow, roots: &HashMap<u32, PathBuf>) -> Self {
+        let path = match roots.get(&row.entry.root) {
+            Some(x) => x.join(&row.entry.path),
+            None => row.entry.path.clone(),
+        };
+        Self {
+            path,
+            size: row.entry.size,
+            mtime: row.entry.mtime,
+            hash: Hash(row.hash),
+        }
+    }
+}
+
+/// Files of `db` in `report`, sorted by path.
+pub fn catalog_entries(db: &IndexDb, report: Report) -> anyhow::Result<Vec<CatalogEntry>> {
+    let roots = root_paths(db)?;
+    let rows = match report {
+        Report::Index => db.select_index_all()?,
+        Report::Diff => db.select_stored_files()?,
+    };
+    let mut entries = rows
+        .iter()
+        .map(|x| CatalogEntry::from_row(x, &roots))
+        .collect::<Vec<_>>();
+    sort_entries(&mut entries, SortKey::Path, false);
+    Ok(entries)
+}
+
+pub fn sort_entries(entries: &mut [CatalogEntry], key: SortKey, reverse: bool) {
+    match key {
+        SortKey::Path => entries.sort_by(|a, b| a.path.cmp(&b.path)),
+        // ties keep the path order
+        SortKey::Size => entries.sort_by(|a, b| (a.size, &a.path).cmp(&(b.size, &b.path))),
+        SortKey::Mtime => entries.sort_by(|a, b| (*a.mtime, &a.path).cmp(&(*b.mtime, &b.path))),
+    }
+    if reverse {
+        entries.reverse();
+    }
+}
+
+fn utc_time(time: FileNanoTime) -> DateTime<Utc> {
+    DateTime::from_timestamp(
+        (*time / 1_000_000_000) as i64,
+        (*time % 1_000_000_000) as u32,
+    )
+    .unwrap_or_default()
+}
+
+/// Local time to the second, for reading.
+pub fn format_time(time: FileNanoTime) -> String {
+    utc_time(time)
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// RFC 3339 in UTC with the full precision, for other programs.
+pub fn format_time_rfc3339(time: FileNanoTime) -> String {
+    utc_time(time).to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+pub fn write_entries(
+    out: &mut impl Write,
+    entries: &[CatalogEntry],
+    format: ListFormat,
+) -> anyhow::Result<()> {
+    match format {
+        ListFormat::Text => {
+            for x in entries {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    x.size,
+                    format_time(x.mtime),
+                    x.hash,
+                    x.path.display()
+                )?;
+            }
+        }
+        ListFormat::Json => {
+            let paths = entries
+                .iter()
+                .map(|x| x.path.to_string_lossy())
+                .collect::<Vec<_>>();
+            let json = entries
+                .iter()
+                .zip(&paths)
+                .map(|(x, path)| JsonEntry {
+                    path,
+                    size: x.size,
+                    mtime: format_time_rfc3339(x.mtime),
+                    hash: x.hash.to_string(),
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut *out, &json)?;
+            writeln!(out)?;
+        }
+        ListFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(["path", "size", "mtime", "hash"])?;
+            for x in entries {
+                writer.write_record([
+                    &*PathBytes::from(&x.path),
+                    x.size.to_string().as_bytes(),
+                    format_time_rfc3339(x.mtime).as_bytes(),
+                    x.hash.to_string().as_bytes(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_export(
+    out: &mut impl Write,
+    entries: &[CatalogEntry],
+    format: ExportFormat,
+) -> anyhow::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(out);
+            for x in entries {
+                writer.write_record([&*PathBytes::from(&x.path), x.size.to_string().as_bytes()])?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Json => write_entries(out, entries, ListFormat::Json)?,
+        ExportFormat::Nul => {
+            for x in entries {
+                out.write_all(&PathBytes::from(&x.path))?;
+                out.write_all(b"\0")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the `index.txt` and `diff.txt` reports of `db` into `dir`.
+pub fn write_catalog(db: &IndexDb, dir: &Path) -> anyhow::Result<()> {
+    for (report, name) in [
+        (Report::Index, CATALOG_INDEX_NAME),
+        (Report::Diff, CATALOG_DIFF_NAME),
+    ] {
+        let mut out = BufWriter::new(File::create(dir.join(name))?);
+        write_export(&mut out, &catalog_entries(db, report)?, ExportFormat::Csv)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn component_name(x: Component<'_>) -> &OsStr {
+    match x {
+        Component::RootDir => OsStr::new("/"),
+        x => x.as_os_str(),
+    }
+}
+
+/// Writes `entries`, which must be sorted by path, as an indented tree. Directories are
+/// those implied by the file paths.
+pub fn write_tree(out: &mut impl Write, entries: &[CatalogEntry]) -> io::Result<()> {
+    let mut current_dir: Vec<Component<'_>> = Vec::new();
+    for x in entries {
+        let dir = x.path.parent().unwrap_or(Path::new(""));
+        let components = dir.components().collect::<Vec<_>>();
+        let common = current_dir
+            .iter()
+            .zip(&components)
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, c) in components.iter().enumerate().skip(common) {
+            let name = component_name(*c);
+            let slash = if name == "/" { "" } else { "/" };
+            writeln!(
+                out,
+                "{:indent$}{}{slash}",
+                "",
+                name.to_string_lossy(),
+                indent = depth * 2
+            )?;
+        }
+        writeln!(
+            out,
+            "{:indent$}{} ({})",
+            "",
+            x.path.file_name().unwrap_or_default().to_string_lossy(),
+            x.size,
+            indent = components.len() * 2
+        )?;
+        current_dir = components;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: impl Into<PathBuf>, size: u64) -> CatalogEntry {
+        CatalogEntry {
+            path: path.into(),
+            size,
+            mtime: FileNanoTime(1_700_000_000_000_000_000),
+            hash: Hash([7; crate::HASH_SIZE]),
+        }
+    }
+
+    fn export(entries: &[CatalogEntry], format: ExportFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_export(&mut out, entries, format).unwrap();
+        out
+    }
+
+    #[test]
+    fn csv_export_quotes_special_paths() {
+        let entries = [
+            entry("plain.txt", 1),
+            entry("a,b.txt", 2),
+            entry("say \"hi\".txt", 3),
+            entry("two\nlines.txt", 4),
+        ];
+        let out = export(&entries, ExportFormat::Csv);
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "plain.txt,1\n\"a,b.txt\",2\n\"say \"\"hi\"\".txt\",3\n\"two\nlines.txt\",4\n"
+        );
+        // and a CSV reader gets the paths back
+        let paths = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(&out[..])
+            .into_records()
+            .map(|x| x.unwrap()[0].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            ["plain.txt", "a,b.txt", "say \"hi\".txt", "two\nlines.txt"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exports_keep_raw_path_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        let raw = b"dir/caf\xe9.txt";
+        let entries = [entry(OsStr::from_bytes(raw), 5)];
+
+        let mut expected = raw.to_vec();
+        expected.extend_from_slice(b",5\n");
+        assert_eq!(export(&entries, ExportFormat::Csv), expected);
+
+        let mut expected = raw.to_vec();
+        expected.push(0);
+        assert_eq!(export(&entries, ExportFormat::Nul), expected);
+    }
+}
@@ -0,0 +1,114 @@
+//! Configuration file with named backup profiles.
+//!
+//! A profile holds the options of a `backup` run, e.g.
+//!
+//! ```toml
+//! [profiles.photos]
+//! source-dirs = ["/home/me/Pictures"]
+//! excludes = ["*.tmp", ".thumbnails"]
+//! media = "bd25"
+//! filter = ["bash", "-c", "zstd | openssl enc -aes-256-cbc -pbkdf2 -pass file:/root/key"]
+//! destinations = ["/media/me/BD-RE", "/mnt/nas/backup/photos"]
+//! ```
+
+use crate::APP_NAME;
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use yeet_ops::yeet;
+
+/// Volume sizes for optical media, leaving room for the file system and the index.
+pub const MEDIA_PRESETS: &[(&str, u64)] = &[
+    ("cd", 680 * 1024 * 1024),
+    ("dvd", 4300 * 1024 * 1024),
+    ("dvd-dl", 7800 * 1024 * 1024),
+    ("bd25", 22 * 1024 * 1024 * 1024),
+    ("bd50", 45 * 1024 * 1024 * 1024),
+    ("bd100", 90 * 1024 * 1024 * 1024),
+];
+
+pub fn media_size(name: &str) -> anyhow::Result<u64> {
+    match MEDIA_PRESETS.iter().find(|(x, _)| *x == name) {
+        Some((_, size)) => Ok(*size),
+        None => {
+            let names = MEDIA_PRESETS.iter().map(|x| x.0).collect::<Vec<_>>();
+            yeet!(anyhow!(
+                "Unknown media preset `{name}`; expected one of: {}",
+                names.join(", ")
+            ));
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/backup-tool/config.toml`
+pub fn default_config_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join(APP_NAME).join("config.toml"))
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Options of a `backup` run. Keys are mostly named like the command line flags, but
+/// repeatable ones are plural (`excludes` for `--exclude`, `include-mounts` for
+/// `--include-mount`), `filter` stands for `--backup-output-filter`, and `destinations` has
+/// no flag.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    pub source_dirs: Vec<PathBuf>,
+    /// Glob patterns of entries to leave out, relative to each source directory
+    pub excludes: Vec<String>,
+    /// Name of a [`MEDIA_PRESETS`] entry to size volumes for
+    pub media: Option<String>,
+    pub chunk_size: Option<String>,
+    /// Takes precedence over `media`
+    pub backup_size: Option<String>,
+    /// External program filter for backup files, as program and arguments
+    pub filter: Option<Vec<String>>,
+    pub state_dir: Option<PathBuf>,
+    /// Candidate parent directories of the output; the first existing one is used, so
+    /// removable media can be listed along with fallbacks
+    pub destinations: Vec<PathBuf>,
+    pub follow_symlinks: bool,
+    pub xattrs: bool,
+    pub strict: bool,
+    pub one_file_system: bool,
+    pub include_mounts: Vec<PathBuf>,
+    /// Write `index.txt` and `diff.txt` into the output directory
+    pub catalog: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Cannot read config file {}: {e}", path.display()))?;
+        toml::from_str(&text).map_err(|e| anyhow!("Invalid config file {}: {e}", path.display()))
+    }
+
+    pub fn profile(&self, name: &str) -> anyhow::Result<&Profile> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("No profile named `{name}` in the config file"))
+    }
+}
+
+impl Profile {
+    /// The first of `destinations` that exists.
+    pub fn pick_destination(&self) -> anyhow::Result<&Path> {
+        match self.destinations.iter().find(|x| x.is_dir()) {
+            Some(x) => Ok(x),
+            None => {
+                yeet!(anyhow!(
+                    "None of the profile destinations exists: {:?}",
+                    self.destinations
+                ));
+            }
+        }
+    }
+}
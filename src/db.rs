@@ -1,10 +1,57 @@
-use crate::{FileEntry, FileNanoTime, PathBytes, SplitInfo, HASH_SIZE};
+use crate::sparse::{decode_holes, encode_holes, stored_size};
+use crate::xattrs::Xattrs;
+use crate::{
+    FileAttrs, FileEntry, FileNanoTime, Hash, InodeId, NodeEntry, PathBytes, Range, SkippedEntry,
+    SplitInfo, HASH_SIZE,
+};
+use anyhow::anyhow;
+use log::info;
+use rusqlite::backup::Progress;
 use rusqlite::fallible_iterator::{FallibleIterator, IteratorExt};
-use rusqlite::{params, Connection, Transaction};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, DatabaseName, OpenFlags, OptionalExtension, Row, Transaction};
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use yeet_ops::yeet;
 
-#[derive(Debug)]
+/// Version of the index database schema this build writes.
+pub const SCHEMA_VERSION: u32 = 12;
+
+/// Upgrade scripts; `MIGRATIONS[i]` upgrades a database from version `i + 1` to `i + 2`.
+///
+/// Version 1 is the unversioned `index.sql` layout.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/v2.sql"),
+    include_str!("../migrations/v3.sql"),
+    include_str!("../migrations/v4.sql"),
+    include_str!("../migrations/v5.sql"),
+    include_str!("../migrations/v6.sql"),
+    include_str!("../migrations/v7.sql"),
+    include_str!("../migrations/v8.sql"),
+    include_str!("../migrations/v9.sql"),
+    include_str!("../migrations/v10.sql"),
+    include_str!("../migrations/v11.sql"),
+    include_str!("../migrations/v12.sql"),
+];
+
+const INDEX_COLUMNS: &str =
+    "path, size, mtime, hash, mode, uid, gid, atime, ctime, btime, dev, ino, root_id";
+/// Selected after the other columns; resolves `xattr_id` to the attribute data.
+const XATTR_DATA_COLUMN: &str = "(select data from xattr where xattr.id = xattr_id)";
+const CHUNK_COLUMNS: &str = "file_hash, chunk_n, chunk_hash, bak_n, offset, size, hole, holes";
+const NODE_COLUMNS: &str =
+    "path, kind, mtime, mode, uid, gid, atime, ctime, btime, link_target, rdev, root_id";
+
+const META_SCHEMA_VERSION: &str = "schema_version";
+const META_TOOL_VERSION: &str = "tool_version";
+const META_CREATED_AT: &str = "created_at";
+const META_CHUNK_SIZE: &str = "chunk_size";
+const META_HASH_ALGORITHM: &str = "hash_algorithm";
+const META_FILTER: &str = "filter";
+const META_OUT_DIR: &str = "out_dir";
+
+#[derive(Debug, Clone)]
 pub struct IndexRow {
     pub entry: FileEntry,
     pub hash: [u8; HASH_SIZE],
@@ -12,11 +59,41 @@ pub struct IndexRow {
 
 pub struct ChunkRow {
     pub file_hash: [u8; HASH_SIZE],
+    /// Position of this chunk in the file, starting from zero
+    pub chunk_n: u32,
+    /// Hash of the stored data
     pub chunk_hash: [u8; HASH_SIZE],
     pub bak_n: i32,
     /// Offset of this chunk in the 'bak' file
     pub offset: u64,
+    /// Size in the file, holes included
     pub size: u64,
+    /// Holes in the chunk, relative to its start; they read as zeros and aren't stored
+    pub holes: Vec<Range>,
+}
+
+impl ChunkRow {
+    /// Bytes of the chunk stored in the 'bak' file.
+    pub fn stored_size(&self) -> u64 {
+        stored_size(self.size, &self.holes)
+    }
+}
+
+/// Information about how an index database was made. Fields are `None` when unknown,
+/// e.g. for databases written before the `meta` table existed.
+#[derive(Default, Debug, Clone)]
+pub struct IndexMeta {
+    pub schema_version: Option<u32>,
+    pub tool_version: Option<String>,
+    /// RFC 3339 timestamp
+    pub created_at: Option<String>,
+    pub chunk_size: Option<u64>,
+    pub hash_algorithm: Option<String>,
+    /// The output filter command the 'bak' files were written through, as a JSON array of
+    /// the program and its arguments
+    pub filter: Option<String>,
+    /// Where the 'bak' files were written, as text
+    pub out_dir: Option<PathBuf>,
 }
 
 pub struct IndexDb {
@@ -24,32 +101,259 @@ pub struct IndexDb {
 }
 
 impl IndexDb {
+    /// Opens or creates an index database for writing, upgrading its schema in place if
+    /// needed.
     pub fn new(path: impl AsRef<Path>, delete_old: bool) -> anyhow::Result<Self> {
-        if delete_old && path.as_ref().exists() {
-            fs::remove_file(path.as_ref())?;
+        let path = path.as_ref();
+        if delete_old && path.exists() {
+            fs::remove_file(path)?;
+        }
+        let mut db = Connection::open(path)?;
+        let version = Self::checked_schema_version(&db, path)?;
+        Self::migrate(&mut db, version)?;
+        Ok(Self { db })
+    }
+
+    /// Opens an existing index database without ever writing to it. An older schema is
+    /// upgraded on a copy in memory, so archived indexes stay as they were made.
+    pub fn open_read_only(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            yeet!(anyhow!("Index database not found: {}", path.display()));
+        }
+        let mut db = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let version = Self::checked_schema_version(&db, path)?;
+        if version == 0 {
+            yeet!(anyhow!("Not an index database: {}", path.display()));
+        }
+        if version < SCHEMA_VERSION {
+            let mut mem_db = Connection::open_in_memory()?;
+            mem_db.restore(DatabaseName::Main, path, None::<fn(Progress)>)?;
+            db = mem_db;
+            Self::migrate(&mut db, version)?;
         }
-        let db = Connection::open(path)?;
-        let sql = include_str!("../index.sql");
-        db.execute_batch(sql)?;
         Ok(Self { db })
     }
 
-    pub fn transaction(&mut self) -> anyhow::Result<IndexDbTx> {
+    fn checked_schema_version(db: &Connection, path: &Path) -> anyhow::Result<u32> {
+        let version = Self::query_schema_version(db)?;
+        if version > SCHEMA_VERSION {
+            yeet!(anyhow!(
+                "Index database {} has schema version {version}, newer than supported ({SCHEMA_VERSION})",
+                path.display()
+            ));
+        }
+        Ok(version)
+    }
+
+    /// Returns 0 for an empty database.
+    fn query_schema_version(db: &Connection) -> anyhow::Result<u32> {
+        let table_exists = |name: &str| -> rusqlite::Result<bool> {
+            db.query_row(
+                "select count(*) from sqlite_master where type = 'table' and name = ?",
+                params![name],
+                |r| Ok(r.get_unwrap::<_, u64>(0) != 0),
+            )
+        };
+        if table_exists("meta")? {
+            let version: Option<String> = db
+                .query_row(
+                    "select value from meta where key = ?",
+                    params![META_SCHEMA_VERSION],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            let Some(version) = version else {
+                yeet!(anyhow!("Missing schema version in the meta table"));
+            };
+            return Ok(version.parse()?);
+        }
+        Ok(if table_exists("index")? { 1 } else { 0 })
+    }
+
+    fn migrate(db: &mut Connection, from: u32) -> anyhow::Result<()> {
+        if from == SCHEMA_VERSION {
+            return Ok(());
+        }
+        let tx = db.transaction()?;
+        if from == 0 {
+            tx.execute_batch(include_str!("../index.sql"))?;
+        } else {
+            info!("Upgrading index database schema: v{from} -> v{SCHEMA_VERSION}");
+        }
+        for sql in &MIGRATIONS[(from.max(1) - 1) as usize..] {
+            tx.execute_batch(sql)?;
+        }
+        set_meta(&tx, META_SCHEMA_VERSION, &SCHEMA_VERSION.to_string())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn read_meta(&self) -> anyhow::Result<IndexMeta> {
+        let get = |key: &str| -> anyhow::Result<Option<String>> {
+            Ok(self
+                .db
+                .query_row("select value from meta where key = ?", params![key], |r| {
+                    r.get(0)
+                })
+                .optional()?)
+        };
+        Ok(IndexMeta {
+            schema_version: get(META_SCHEMA_VERSION)?.map(|x| x.parse()).transpose()?,
+            tool_version: get(META_TOOL_VERSION)?,
+            created_at: get(META_CREATED_AT)?,
+            chunk_size: get(META_CHUNK_SIZE)?.map(|x| x.parse()).transpose()?,
+            hash_algorithm: get(META_HASH_ALGORITHM)?,
+            filter: get(META_FILTER)?,
+            out_dir: get(META_OUT_DIR)?.map(PathBuf::from),
+        })
+    }
+
+    /// Writes all the known fields except `schema_version`, which is maintained by [`IndexDb`].
+    pub fn write_meta(&self, meta: &IndexMeta) -> anyhow::Result<()> {
+        let fields = [
+            (META_TOOL_VERSION, meta.tool_version.clone()),
+            (META_CREATED_AT, meta.created_at.clone()),
+            (META_CHUNK_SIZE, meta.chunk_size.map(|x| x.to_string())),
+            (META_HASH_ALGORITHM, meta.hash_algorithm.clone()),
+            (META_FILTER, meta.filter.clone()),
+            (
+                META_OUT_DIR,
+                meta.out_dir.as_ref().map(|x| x.to_string_lossy().into()),
+            ),
+        ];
+        for (key, value) in fields {
+            if let Some(v) = value {
+                set_meta(&self.db, key, &v)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn transaction(&mut self) -> anyhow::Result<IndexDbTx<'_>> {
         Ok(IndexDbTx(self.db.transaction()?))
     }
 
     pub fn select_index_all(&self) -> anyhow::Result<Vec<IndexRow>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {INDEX_COLUMNS}, {XATTR_DATA_COLUMN} from `index`"
+        ))?;
+        let map = stmt.query_map(params![], map_index_row)?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    pub fn select_index_by_path(
+        &self,
+        root: u32,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<Option<IndexRow>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {INDEX_COLUMNS}, {XATTR_DATA_COLUMN} from `index` where root_id = ? and path = ?"
+        ))?;
+        Ok(stmt
+            .query_row(
+                params![root, &*PathBytes::from(path.as_ref())],
+                map_index_row,
+            )
+            .optional()?)
+    }
+
+    /// All paths having the content `hash`.
+    pub fn select_files_for_hash(&self, hash: &[u8; HASH_SIZE]) -> anyhow::Result<Vec<IndexRow>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {INDEX_COLUMNS}, {XATTR_DATA_COLUMN} from `index` where hash = ?"
+        ))?;
+        let map = stmt.query_map(params![hash], map_index_row)?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    /// Paths whose content is stored in the 'bak' files of this generation, as opposed to
+    /// an earlier one.
+    pub fn select_stored_files(&self) -> anyhow::Result<Vec<IndexRow>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {INDEX_COLUMNS}, {XATTR_DATA_COLUMN} from `index` where hash in (select file_hash from chunk)"
+        ))?;
+        let map = stmt.query_map(params![], map_index_row)?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    /// Hashes of the contents stored in the 'bak' files of this generation.
+    pub fn select_stored_hashes(&self) -> anyhow::Result<HashSet<Hash>> {
+        let mut stmt = self
+            .db
+            .prepare_cached("select distinct file_hash from chunk")?;
+        let map = stmt.query_map(params![], |r| Ok(Hash(r.get(0)?)))?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    /// Chunks of a file stored in this generation, in file order. Empty if the file content
+    /// is not stored in this generation.
+    pub fn select_chunks_for_file(
+        &self,
+        file_hash: &[u8; HASH_SIZE],
+    ) -> anyhow::Result<Vec<ChunkRow>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {CHUNK_COLUMNS} from chunk where file_hash = ? order by chunk_n"
+        ))?;
+        let map = stmt.query_map(params![file_hash], map_chunk_row)?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    pub fn select_chunk_all(&self) -> anyhow::Result<Vec<ChunkRow>> {
+        let mut stmt = self
+            .db
+            .prepare_cached(&format!("select {CHUNK_COLUMNS} from chunk"))?;
+        let map = stmt.query_map(params![], map_chunk_row)?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    /// Groups of paths that are hard links of each other.
+    pub fn select_hard_link_groups(&self) -> anyhow::Result<Vec<Vec<IndexRow>>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {INDEX_COLUMNS}, {XATTR_DATA_COLUMN} from `index` where ino is not null order by dev, ino"
+        ))?;
+        let map = stmt.query_map(params![], map_index_row)?;
+        let rows: Vec<IndexRow> = map.into_iter().transpose_into_fallible().collect()?;
+        Ok(rows
+            .chunk_by(|a, b| a.entry.hard_link == b.entry.hard_link)
+            .filter(|x| x.len() > 1)
+            .map(|x| x.to_vec())
+            .collect())
+    }
+
+    /// Source roots by id. Empty for sets made before roots were recorded, which have the
+    /// single root 0.
+    pub fn select_roots(&self) -> anyhow::Result<Vec<(u32, PathBuf)>> {
+        let mut stmt = self
+            .db
+            .prepare_cached("select id, path from root order by id")?;
+        let map = stmt.query_map(params![], |r| {
+            Ok((r.get(0)?, PathBytes(r.get(1)?).into_path_buf()))
+        })?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    pub fn select_node_all(&self) -> anyhow::Result<Vec<NodeEntry>> {
+        let mut stmt = self.db.prepare_cached(&format!(
+            "select {NODE_COLUMNS}, {XATTR_DATA_COLUMN} from node"
+        ))?;
+        let map = stmt.query_map(params![], map_node_row)?;
+        Ok(map.into_iter().transpose_into_fallible().collect()?)
+    }
+
+    pub fn select_skipped_all(&self) -> anyhow::Result<Vec<SkippedEntry>> {
         let mut stmt = self
             .db
-            .prepare_cached("select path, size, mtime, hash from `index`")?;
+            .prepare_cached("select path, reason, time, root_id from skipped")?;
         let map = stmt.query_map(params![], |r| {
-            Ok(IndexRow {
-                entry: FileEntry {
-                    path: PathBytes(r.get_unwrap(0)).into_path_buf(),
-                    size: r.get_unwrap(1),
-                    mtime: FileNanoTime(r.get_unwrap(2)),
-                },
-                hash: r.get_unwrap(3),
+            Ok(SkippedEntry {
+                path: PathBytes(r.get(0)?).into_path_buf(),
+                reason: r.get(1)?,
+                time: r.get(2)?,
+                root: r.get(3)?,
             })
         })?;
         Ok(map.into_iter().transpose_into_fallible().collect()?)
@@ -75,28 +379,107 @@ pub struct IndexDbTx<'a>(pub Transaction<'a>);
 
 impl<'a> IndexDbTx<'a> {
     pub fn insert_index_row(&self, row: &IndexRow) -> anyhow::Result<()> {
-        let mut stmt = self
-            .0
-            .prepare_cached("insert into `index` (path, size, mtime, hash) values (?, ?, ?, ?)")?;
+        let mut stmt = self.0.prepare_cached(&format!(
+            "insert into `index` ({INDEX_COLUMNS}, xattr_id) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        ))?;
+        let xattr_id = self.insert_xattrs(row.entry.xattrs.as_ref())?;
+        let attrs = row.entry.attrs.as_ref();
         stmt.insert(params![
             &*PathBytes::from(&row.entry.path),
             row.entry.size,
             *row.entry.mtime,
-            row.hash
+            row.hash,
+            attrs.map(|x| x.mode),
+            attrs.map(|x| x.uid),
+            attrs.map(|x| x.gid),
+            attrs.map(|x| *x.atime),
+            attrs.map(|x| *x.ctime),
+            attrs.and_then(|x| x.btime).map(|x| *x),
+            // stored as signed integers; inode numbers may use the highest bit
+            row.entry.hard_link.map(|x| x.dev as i64),
+            row.entry.hard_link.map(|x| x.ino as i64),
+            row.entry.root,
+            xattr_id,
         ])?;
         Ok(())
     }
 
-    pub fn insert_chunk_row(&self, row: &ChunkRow) -> anyhow::Result<()> {
+    pub fn insert_node_row(&self, node: &NodeEntry) -> anyhow::Result<()> {
+        let mut stmt = self.0.prepare_cached(&format!(
+            "insert into node ({NODE_COLUMNS}, xattr_id) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        ))?;
+        let xattr_id = self.insert_xattrs(node.xattrs.as_ref())?;
+        let attrs = node.attrs.as_ref();
+        stmt.insert(params![
+            &*PathBytes::from(&node.path),
+            node.kind.as_str(),
+            *node.mtime,
+            attrs.map(|x| x.mode),
+            attrs.map(|x| x.uid),
+            attrs.map(|x| x.gid),
+            attrs.map(|x| *x.atime),
+            attrs.map(|x| *x.ctime),
+            attrs.and_then(|x| x.btime).map(|x| *x),
+            node.link_target.as_ref().map(|x| PathBytes::from(x).0),
+            node.rdev.map(|x| x as i64),
+            node.root,
+            xattr_id,
+        ])?;
+        Ok(())
+    }
+
+    pub fn insert_skipped_row(&self, entry: &SkippedEntry) -> anyhow::Result<()> {
         let mut stmt = self.0.prepare_cached(
-            "insert into chunk (file_hash, chunk_hash, bak_n, offset, size) values (?, ?, ?, ?, ?)",
+            "insert into skipped (path, reason, time, root_id) values (?, ?, ?, ?)",
         )?;
+        stmt.insert(params![
+            &*PathBytes::from(&entry.path),
+            entry.reason,
+            entry.time,
+            entry.root
+        ])?;
+        Ok(())
+    }
+
+    pub fn insert_root_row(&self, id: u32, path: &Path) -> anyhow::Result<()> {
+        let mut stmt = self
+            .0
+            .prepare_cached("insert into root (id, path) values (?, ?)")?;
+        stmt.insert(params![id, &*PathBytes::from(path)])?;
+        Ok(())
+    }
+
+    /// Stores an attribute set once and returns its id. Empty sets are not stored.
+    fn insert_xattrs(&self, xattrs: Option<&Xattrs>) -> anyhow::Result<Option<i64>> {
+        let Some(xattrs) = xattrs.filter(|x| !x.is_empty()) else {
+            return Ok(None);
+        };
+        let hash = xattrs.hash();
+        self.0
+            .prepare_cached("insert or ignore into xattr (hash, data) values (?, ?)")?
+            .execute(params![*hash, xattrs.encode()])?;
+        let id = self
+            .0
+            .prepare_cached("select id from xattr where hash = ?")?
+            .query_row(params![*hash], |r| r.get(0))?;
+        Ok(Some(id))
+    }
+
+    pub fn insert_chunk_row(&self, row: &ChunkRow) -> anyhow::Result<()> {
+        let mut stmt = self.0.prepare_cached(&format!(
+            "insert into chunk ({CHUNK_COLUMNS}) values (?, ?, ?, ?, ?, ?, ?, ?)"
+        ))?;
+        let hole = row.size != 0 && row.stored_size() == 0;
+        let holes = (!row.holes.is_empty()).then(|| encode_holes(&row.holes));
         stmt.insert(params![
             row.file_hash,
+            row.chunk_n,
             row.chunk_hash,
             row.bak_n,
             row.offset,
-            row.size
+            row.size,
+            hole,
+            holes
         ])?;
         Ok(())
     }
@@ -104,16 +487,201 @@ impl<'a> IndexDbTx<'a> {
     pub fn insert_file_split_info(&self, splits: &[SplitInfo]) -> anyhow::Result<()> {
         for x in splits {
             let file_hash = x.file_hash;
-            for x in &x.chunks {
+            for (chunk_n, x) in x.chunks.iter().enumerate() {
                 self.insert_chunk_row(&ChunkRow {
                     file_hash: *file_hash,
+                    chunk_n: chunk_n as u32,
                     bak_n: x.bak_n,
                     chunk_hash: *x.hash,
                     offset: x.offset,
                     size: x.size,
+                    holes: x.holes.clone(),
                 })?;
             }
         }
         Ok(())
     }
 }
+
+fn map_index_row(r: &Row) -> rusqlite::Result<IndexRow> {
+    Ok(IndexRow {
+        entry: FileEntry {
+            path: PathBytes(r.get_unwrap(0)).into_path_buf(),
+            size: r.get_unwrap(1),
+            mtime: FileNanoTime(r.get_unwrap(2)),
+            attrs: map_file_attrs(r, 4)?,
+            hard_link: match (r.get::<_, Option<i64>>(10)?, r.get::<_, Option<i64>>(11)?) {
+                (Some(dev), Some(ino)) => Some(InodeId {
+                    dev: dev as u64,
+                    ino: ino as u64,
+                }),
+                _ => None,
+            },
+            root: r.get(12)?,
+            xattrs: map_xattrs(r, 13)?,
+        },
+        hash: r.get_unwrap(3),
+    })
+}
+
+fn map_node_row(r: &Row) -> rusqlite::Result<NodeEntry> {
+    let kind: String = r.get(1)?;
+    Ok(NodeEntry {
+        path: PathBytes(r.get(0)?).into_path_buf(),
+        kind: kind.parse().map_err(|e: anyhow::Error| {
+            rusqlite::Error::FromSqlConversionFailure(1, Type::Text, e.into())
+        })?,
+        mtime: FileNanoTime(r.get(2)?),
+        attrs: map_file_attrs(r, 3)?,
+        link_target: r
+            .get::<_, Option<Vec<u8>>>(9)?
+            .map(|x| PathBytes(x).into_path_buf()),
+        rdev: r.get::<_, Option<i64>>(10)?.map(|x| x as u64),
+        root: r.get(11)?,
+        xattrs: map_xattrs(r, 12)?,
+    })
+}
+
+fn map_xattrs(r: &Row, idx: usize) -> rusqlite::Result<Option<Xattrs>> {
+    let Some(data) = r.get::<_, Option<Vec<u8>>>(idx)? else {
+        return Ok(None);
+    };
+    Xattrs::decode(&data)
+        .map(Some)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Blob, e.into()))
+}
+
+/// Reads the columns `mode, uid, gid, atime, ctime, btime` starting at `start`.
+fn map_file_attrs(r: &Row, start: usize) -> rusqlite::Result<Option<FileAttrs>> {
+    let Some(mode) = r.get::<_, Option<u32>>(start)? else {
+        return Ok(None);
+    };
+    Ok(Some(FileAttrs {
+        mode,
+        uid: r.get(start + 1)?,
+        gid: r.get(start + 2)?,
+        atime: FileNanoTime(r.get(start + 3)?),
+        ctime: FileNanoTime(r.get(start + 4)?),
+        btime: r.get::<_, Option<u64>>(start + 5)?.map(FileNanoTime),
+    }))
+}
+
+fn map_chunk_row(r: &Row) -> rusqlite::Result<ChunkRow> {
+    let size = r.get_unwrap(5);
+    let holes = match (r.get::<_, bool>(6)?, r.get::<_, Option<Vec<u8>>>(7)?) {
+        (_, Some(x)) => decode_holes(&x, size)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(7, Type::Blob, e.into()))?,
+        (true, None) => vec![Range { start: 0, size }],
+        (false, None) => Vec::new(),
+    };
+    Ok(ChunkRow {
+        file_hash: r.get_unwrap(0),
+        chunk_n: r.get_unwrap(1),
+        chunk_hash: r.get_unwrap(2),
+        bak_n: r.get_unwrap(3),
+        offset: r.get_unwrap(4),
+        size,
+        holes,
+    })
+}
+
+fn set_meta(db: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+    db.execute(
+        "insert or replace into meta (key, value) values (?, ?)",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database file under the temporary directory, removed when dropped.
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("backup-tool-test-{}-{name}.db", std::process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const HASH_A: [u8; HASH_SIZE] = [0xa; HASH_SIZE];
+    const HASH_B: [u8; HASH_SIZE] = [0xb; HASH_SIZE];
+
+    /// A v1 database: `a.bin` of 10 bytes in two chunks, stored twice in the generation, and
+    /// `b.bin` of 5 bytes in one.
+    fn create_v1(path: &Path) {
+        let db = Connection::open(path).unwrap();
+        db.execute_batch(include_str!("../index.sql")).unwrap();
+        for (path, size, hash) in [("a.bin", 10, HASH_A), ("b.bin", 5, HASH_B)] {
+            db.execute(
+                "insert into `index` (path, size, mtime, hash) values (?, ?, ?, ?)",
+                params![path.as_bytes(), size, 1_700_000_000_u64, hash],
+            )
+            .unwrap();
+        }
+        let chunks: &[([u8; HASH_SIZE], u8, u64, u64)] = &[
+            (HASH_A, 1, 0, 6),
+            (HASH_A, 2, 6, 4),
+            (HASH_B, 3, 10, 5),
+            // the duplicated copy of `a.bin`
+            (HASH_A, 1, 15, 6),
+            (HASH_A, 2, 21, 4),
+        ];
+        for (file_hash, chunk, offset, size) in chunks {
+            db.execute(
+                "insert into chunk (file_hash, chunk_hash, bak_n, offset, size) values (?, ?, 0, ?, ?)",
+                params![file_hash, [*chunk; HASH_SIZE], offset, size],
+            )
+            .unwrap();
+        }
+    }
+
+    fn check_upgraded(db: &IndexDb) {
+        assert_eq!(db.read_meta().unwrap().schema_version, Some(SCHEMA_VERSION));
+        let chunks = db.select_chunks_for_file(&HASH_A).unwrap();
+        let layout = chunks
+            .iter()
+            .map(|x| (x.chunk_n, x.chunk_hash[0], x.offset, x.size, x.holes.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(layout, [(0, 1, 0, 6, 0), (1, 2, 6, 4, 0)]);
+        let chunks = db.select_chunks_for_file(&HASH_B).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].chunk_n, chunks[0].offset), (0, 10));
+
+        let row = db.select_index_by_path(0, "a.bin").unwrap().unwrap();
+        assert_eq!((row.entry.size, row.hash), (10, HASH_A));
+        assert!(row.entry.attrs.is_none());
+        assert!(db.select_roots().unwrap().is_empty());
+        assert!(db.select_node_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn v1_upgrades_to_current() {
+        let file = TempDb::new("upgrade");
+        create_v1(&file.0);
+        check_upgraded(&IndexDb::new(&file.0, false).unwrap());
+        // the upgrade was written
+        let db = Connection::open(&file.0).unwrap();
+        assert_eq!(IndexDb::query_schema_version(&db).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn read_only_open_leaves_the_file_alone() {
+        let file = TempDb::new("read-only");
+        create_v1(&file.0);
+        let before = fs::read(&file.0).unwrap();
+        check_upgraded(&IndexDb::open_read_only(&file.0).unwrap());
+        assert_eq!(fs::read(&file.0).unwrap(), before);
+    }
+}
@@ -0,0 +1,315 @@
+//! Comparing the files of two generations, or of a generation and the source tree.
+
+use crate::db::IndexDb;
+use crate::{compute_file_hash, FileEntry, FileNanoTime, Hash, SourceIndex};
+use log::error;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Root id and path relative to that root.
+pub type FileKey = (u32, PathBuf);
+
+#[derive(Debug, Clone)]
+pub struct DiffFile {
+    pub size: u64,
+    pub mtime: FileNanoTime,
+    /// `None` for files of a scan that weren't hashed
+    pub hash: Option<Hash>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Change {
+    Added {
+        key: FileKey,
+        size: u64,
+    },
+    Removed {
+        key: FileKey,
+        size: u64,
+    },
+    Modified {
+        key: FileKey,
+        old_size: u64,
+        new_size: u64,
+    },
+    /// Same content at another path
+    Moved {
+        from: FileKey,
+        to: FileKey,
+        size: u64,
+    },
+}
+
+impl Change {
+    /// Change of the total file size this makes.
+    pub fn size_delta(&self) -> i64 {
+        match self {
+            Change::Added { size, .. } => *size as i64,
+            Change::Removed { size, .. } => -(*size as i64),
+            Change::Modified {
+                old_size, new_size, ..
+            } => *new_size as i64 - *old_size as i64,
+            Change::Moved { .. } => 0,
+        }
+    }
+}
+
+/// How a file is recognized at another path: by content if both sides are hashed,
+/// otherwise by size and mtime, which a rename keeps.
+#[derive(Hash, Eq, PartialEq)]
+enum Identity {
+    Hash(Hash),
+    Metadata(u64, FileNanoTime),
+}
+
+impl DiffFile {
+    fn same_content(&self, other: &DiffFile) -> bool {
+        match (self.hash, other.hash) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.size == other.size && self.mtime == other.mtime,
+        }
+    }
+}
+
+pub fn index_files_of(db: &IndexDb) -> anyhow::Result<HashMap<FileKey, DiffFile>> {
+    Ok(db
+        .select_index_all()?
+        .into_iter()
+        .map(|x| {
+            let file = DiffFile {
+                size: x.entry.size,
+                mtime: x.entry.mtime,
+                hash: Some(Hash(x.hash)),
+            };
+            ((x.entry.root, x.entry.path), file)
+        })
+        .collect())
+}
+
+/// Files of a fresh scan. With `hash`, files whose metadata differs from `old` are hashed,
+/// so touched but unchanged files and moves are told apart the way a differential backup
+/// does; the others take their hash from `old`.
+pub fn scanned_files(
+    scan: &SourceIndex,
+    source_dirs: &[PathBuf],
+    old: &HashMap<FileKey, DiffFile>,
+    hash: bool,
+) -> HashMap<FileKey, DiffFile> {
+    let mut files = HashMap::new();
+    for entry in &scan.files {
+        let key = (entry.root, entry.path.clone());
+        let mut file = DiffFile {
+            size: entry.size,
+            mtime: entry.mtime,
+            hash: None,
+        };
+        match old.get(&key) {
+            Some(x) if x.size == file.size && x.mtime == file.mtime => file.hash = x.hash,
+            _ if hash => file.hash = hash_entry(entry, source_dirs),
+            _ => {}
+        }
+        files.insert(key, file);
+    }
+    files
+}
+
+fn hash_entry(entry: &FileEntry, source_dirs: &[PathBuf]) -> Option<Hash> {
+    let path = entry.full_path(source_dirs);
+    compute_file_hash(&path)
+        .inspect_err(|e| error!("Failed to hash {}: {e}", path.display()))
+        .ok()
+}
+
+/// Changes from `old` to `new`, sorted by path.
+pub fn compare(old: &HashMap<FileKey, DiffFile>, new: &HashMap<FileKey, DiffFile>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let mut added = Vec::new();
+    for (key, file) in new {
+        match old.get(key) {
+            None => added.push((key, file)),
+            Some(x) if !x.same_content(file) => changes.push(Change::Modified {
+                key: key.clone(),
+                old_size: x.size,
+                new_size: file.size,
+            }),
+            Some(_) => {}
+        }
+    }
+
+    // removed files by both identities, as added ones may lack a hash
+    let mut removed_by_identity: HashMap<Identity, Vec<&FileKey>> = HashMap::new();
+    let mut removed = old
+        .iter()
+        .filter(|(k, _)| !new.contains_key(*k))
+        .collect::<Vec<_>>();
+    removed.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, file) in &removed {
+        // empty files all look alike
+        if file.size == 0 {
+            continue;
+        }
+        if let Some(h) = file.hash {
+            removed_by_identity
+                .entry(Identity::Hash(h))
+                .or_default()
+                .push(key);
+        }
+        removed_by_identity
+            .entry(Identity::Metadata(file.size, file.mtime))
+            .or_default()
+            .push(key);
+    }
+
+    added.sort_by(|a, b| a.0.cmp(b.0));
+    let mut moved_from: HashSet<&FileKey> = HashSet::new();
+    for (key, file) in added {
+        let identity = match file.hash {
+            Some(h) => Identity::Hash(h),
+            None => Identity::Metadata(file.size, file.mtime),
+        };
+        let from = if file.size == 0 {
+            None
+        } else {
+            removed_by_identity.get_mut(&identity).and_then(|x| {
+                // the same old path may also be listed under its other identity
+                let i = x.iter().position(|k| !moved_from.contains(*k))?;
+                Some(x.remove(i))
+            })
+        };
+        match from {
+            Some(from) => {
+                moved_from.insert(from);
+                changes.push(Change::Moved {
+                    from: from.clone(),
+                    to: key.clone(),
+                    size: file.size,
+                });
+            }
+            None => changes.push(Change::Added {
+                key: key.clone(),
+                size: file.size,
+            }),
+        }
+    }
+    for (key, file) in removed {
+        if !moved_from.contains(key) {
+            changes.push(Change::Removed {
+                key: key.clone(),
+                size: file.size,
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| sort_key(a).cmp(sort_key(b)));
+    changes
+}
+
+fn sort_key(change: &Change) -> &FileKey {
+    match change {
+        Change::Added { key, .. } | Change::Removed { key, .. } | Change::Modified { key, .. } => {
+            key
+        }
+        Change::Moved { to, .. } => to,
+    }
+}
+
+/// Display path of `key`: joined to its root in sets with several roots.
+pub fn display_key(roots: &HashMap<u32, PathBuf>, key: &FileKey) -> PathBuf {
+    match roots.get(&key.0) {
+        Some(x) => x.join(&key.1),
+        None => key.1.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(size: u64, mtime: u64, hash: Option<u8>) -> DiffFile {
+        DiffFile {
+            size,
+            mtime: FileNanoTime(mtime),
+            hash: hash.map(|x| Hash([x; crate::HASH_SIZE])),
+        }
+    }
+
+    fn files(list: &[(&str, DiffFile)]) -> HashMap<FileKey, DiffFile> {
+        list.iter()
+            .map(|(path, x)| ((0, PathBuf::from(path)), x.clone()))
+            .collect()
+    }
+
+    /// One line per change, e.g. `moved a -> b`.
+    fn summary(changes: &[Change]) -> Vec<String> {
+        changes
+            .iter()
+            .map(|x| match x {
+                Change::Added { key, .. } => format!("added {}", key.1.display()),
+                Change::Removed { key, .. } => format!("removed {}", key.1.display()),
+                Change::Modified { key, .. } => format!("modified {}", key.1.display()),
+                Change::Moved { from, to, .. } => {
+                    format!("moved {} -> {}", from.1.display(), to.1.display())
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hashed_files_move_by_content() {
+        let old = files(&[("a", file(5, 1, Some(1))), ("b", file(5, 1, Some(2)))]);
+        // `c` is `a` touched; `d` has the metadata of `b` but other content
+        let new = files(&[("c", file(5, 9, Some(1))), ("d", file(5, 1, Some(3)))]);
+        assert_eq!(
+            summary(&compare(&old, &new)),
+            ["removed b", "moved a -> c", "added d"]
+        );
+    }
+
+    #[test]
+    fn unhashed_files_move_by_metadata() {
+        let old = files(&[("a", file(5, 1, Some(1))), ("b", file(5, 2, Some(2)))]);
+        let new = files(&[("c", file(5, 1, None)), ("d", file(5, 3, None))]);
+        assert_eq!(
+            summary(&compare(&old, &new)),
+            ["removed b", "moved a -> c", "added d"]
+        );
+    }
+
+    #[test]
+    fn modified_needs_other_content() {
+        let old = files(&[
+            ("same", file(5, 1, Some(1))),
+            ("touched", file(5, 1, Some(2))),
+            ("changed", file(5, 1, Some(3))),
+            ("unhashed", file(5, 1, Some(4))),
+            ("resized", file(5, 1, Some(5))),
+        ]);
+        let new = files(&[
+            ("same", file(5, 1, Some(1))),
+            ("touched", file(5, 9, Some(2))),
+            ("changed", file(5, 1, Some(6))),
+            // unhashed scans compare by size and mtime
+            ("unhashed", file(5, 1, None)),
+            ("resized", file(6, 1, None)),
+        ]);
+        assert_eq!(
+            summary(&compare(&old, &new)),
+            ["modified changed", "modified resized"]
+        );
+    }
+
+    #[test]
+    fn empty_files_are_not_moves() {
+        let old = files(&[("a", file(0, 1, Some(0)))]);
+        let new = files(&[("b", file(0, 1, Some(0)))]);
+        assert_eq!(summary(&compare(&old, &new)), ["removed a", "added b"]);
+    }
+
+    #[test]
+    fn removed_file_moves_once() {
+        // `a` is listed under both identities; only one added file may take it
+        let old = files(&[("a", file(5, 1, Some(1)))]);
+        let new = files(&[("b", file(5, 2, Some(1))), ("c", file(5, 1, None))]);
+        assert_eq!(summary(&compare(&old, &new)), ["moved a -> b", "added c"]);
+    }
+}
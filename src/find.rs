@@ -0,0 +1,118 @@
+//! Searching the files of every generation of a state directory.
+
+use crate::catalog::root_paths;
+use crate::db::IndexRow;
+use crate::diff::display_key;
+use crate::history::{Generation, Storage, Stored};
+use crate::{FileNanoTime, Hash, HASH_SIZE};
+use globset::GlobMatcher;
+use lazy_regex::Regex;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+pub enum PathPattern {
+    /// Glob matched against the file name
+    Name(GlobMatcher),
+    /// Glob matched against the whole path
+    Path(GlobMatcher),
+    /// Regular expression searched for in the path
+    Regex(Regex),
+}
+
+impl PathPattern {
+    fn is_match(&self, path: &Path) -> bool {
+        match self {
+            PathPattern::Name(x) => path.file_name().is_some_and(|name| x.is_match(name)),
+            PathPattern::Path(x) => x.is_match(path),
+            PathPattern::Regex(x) => x.is_match(&path.to_string_lossy()),
+        }
+    }
+}
+
+/// Conditions a file must all meet; unset ones match any file.
+#[derive(Default)]
+pub struct FindQuery {
+    /// Matched against the path as listed, see [`display_key`]
+    pub pattern: Option<PathPattern>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Inclusive bounds of the mtime
+    pub modified_after: Option<FileNanoTime>,
+    pub modified_before: Option<FileNanoTime>,
+    pub hash: Option<Hash>,
+}
+
+impl FindQuery {
+    fn matches(&self, path: &Path, row: &IndexRow) -> bool {
+        let size = row.entry.size;
+        let mtime = *row.entry.mtime;
+        self.pattern.as_ref().is_none_or(|x| x.is_match(path))
+            && self.min_size.is_none_or(|x| size >= x)
+            && self.max_size.is_none_or(|x| size <= x)
+            && self.modified_after.is_none_or(|x| mtime >= *x)
+            && self.modified_before.is_none_or(|x| mtime <= *x)
+            && self.hash.is_none_or(|x| row.hash == *x)
+    }
+}
+
+/// A version of a file: one content at one path, across the generations it appears in.
+pub struct FindMatch {
+    pub path: PathBuf,
+    /// As in the latest generation holding it; the mtime may differ in earlier ones
+    pub row: IndexRow,
+    /// Indexes into the generations, oldest first
+    pub generations: Vec<usize>,
+    /// The latest generation storing the content up to the last of `generations`. `None` if
+    /// not found, e.g. for an empty file.
+    pub stored_in: Option<Stored>,
+}
+
+/// Versions of the files matching `query` in any of `generations`, sorted by path.
+pub fn find(generations: &[Generation], query: &FindQuery) -> anyhow::Result<Vec<FindMatch>> {
+    let mut found: BTreeMap<(PathBuf, [u8; HASH_SIZE]), FindMatch> = BTreeMap::new();
+    for (i, g) in generations.iter().enumerate() {
+        let db = g.open()?;
+        let roots = root_paths(&db)?;
+        let rows = match &query.hash {
+            Some(x) => db.select_files_for_hash(x)?,
+            None => db.select_index_all()?,
+        };
+        for row in rows {
+            let path = display_key(&roots, &(row.entry.root, row.entry.path.clone()));
+            if !query.matches(&path, &row) {
+                continue;
+            }
+            match found.entry((path.clone(), row.hash)) {
+                Entry::Occupied(mut x) => {
+                    let x = x.get_mut();
+                    x.row = row;
+                    x.generations.push(i);
+                }
+                Entry::Vacant(x) => {
+                    x.insert(FindMatch {
+                        path,
+                        row,
+                        generations: vec![i],
+                        stored_in: None,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut matches = found.into_values().collect::<Vec<_>>();
+    let Some(until) = matches.iter().filter_map(|x| x.generations.last()).max() else {
+        return Ok(matches);
+    };
+    let hashes = matches
+        .iter()
+        .map(|x| Hash(x.row.hash))
+        .collect::<HashSet<_>>();
+    let storage = Storage::scan(generations, *until, &hashes)?;
+    for x in &mut matches {
+        let last = x.generations[x.generations.len() - 1];
+        x.stored_in = storage.stored_in(&Hash(x.row.hash), last);
+    }
+    Ok(matches)
+}
@@ -0,0 +1,302 @@
+//! Versions of a file across the generations kept in a state directory.
+
+use crate::db::{IndexDb, IndexRow};
+use crate::{index_list, Hash};
+use anyhow::anyhow;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use yeet_ops::yeet;
+
+/// A backup generation: one index database of a state directory.
+pub struct Generation {
+    /// File name of the index, e.g. `index_20260301_120000`
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// All generations of `state_dir`, oldest first. None is opened yet.
+pub fn list_generations(state_dir: &Path) -> anyhow::Result<Vec<Generation>> {
+    Ok(index_list(state_dir)?
+        .into_iter()
+        .map(|path| Generation {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+            path,
+        })
+        .collect())
+}
+
+impl Generation {
+    /// Opens the index read-only. One of an older schema is upgraded in a copy in memory,
+    /// so generations are best opened one at a time.
+    pub fn open(&self) -> anyhow::Result<IndexDb> {
+        IndexDb::open_read_only(&self.path)
+    }
+
+    /// Output directory holding its 'bak' volumes, if recorded.
+    pub fn out_dir(&self) -> anyhow::Result<Option<PathBuf>> {
+        Ok(self.open()?.read_meta()?.out_dir)
+    }
+
+    /// Local time the backup was made, from the index name.
+    pub fn time(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(&self.name, "index_%Y%m%d_%H%M%S").ok()
+    }
+}
+
+/// Parses a point in time: a local date, optionally with a time. A date alone means the end
+/// of that day.
+pub fn parse_time(s: &str) -> Option<NaiveDateTime> {
+    parse_date_time(s, NaiveTime::from_hms_opt(23, 59, 59)?)
+}
+
+/// Like [`parse_time`], but a date alone means the start of that day.
+pub fn parse_start_time(s: &str) -> Option<NaiveDateTime> {
+    parse_date_time(s, NaiveTime::MIN)
+}
+
+fn parse_date_time(s: &str, time_of_day: NaiveTime) -> Option<NaiveDateTime> {
+    const FORMATS: &[&str] = &[
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ];
+    if let Some(x) = FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+    {
+        return Some(x);
+    }
+    Some(
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()?
+            .and_time(time_of_day),
+    )
+}
+
+/// Picks a generation by its name, or the latest one made at or before a point in time
+/// accepted by [`parse_time`].
+pub fn select_generation(generations: &[Generation], at: &str) -> anyhow::Result<usize> {
+    if let Some(i) = generations.iter().position(|x| x.name == at) {
+        return Ok(i);
+    }
+    let Some(time) = parse_time(at) else {
+        yeet!(anyhow!(
+            "`{at}` is neither a generation name nor a date like 2026-03-01 or 2026-03-01 12:00"
+        ));
+    };
+    match generations
+        .iter()
+        .rposition(|x| x.time().is_some_and(|t| t <= time))
+    {
+        Some(i) => Ok(i),
+        None => yeet!(anyhow!("No generation was made by {time}")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStatus {
+    /// First seen, or back after being removed
+    Added,
+    Modified,
+    /// Same content with another mtime
+    Touched,
+    Unchanged,
+    /// Not in this generation, but in the one before
+    Removed,
+}
+
+/// Where a content is stored.
+pub struct Stored {
+    /// Index of the generation whose volumes hold it
+    pub generation: usize,
+    /// Output directory of that generation, if recorded
+    pub out_dir: Option<PathBuf>,
+    /// Numbers of its 'bak' volumes holding chunks of the content
+    pub volumes: Vec<i32>,
+}
+
+/// Which generations store some contents, found by opening one generation at a time.
+pub struct Storage {
+    out_dirs: Vec<Option<PathBuf>>,
+    /// Generations storing each content, oldest first, with the volumes holding it
+    stored: HashMap<Hash, Vec<(usize, Vec<i32>)>>,
+}
+
+impl Storage {
+    /// Looks for `hashes` in `generations[..=until]`.
+    pub fn scan(
+        generations: &[Generation],
+        until: usize,
+        hashes: &HashSet<Hash>,
+    ) -> anyhow::Result<Self> {
+        let mut storage = Storage {
+            out_dirs: Vec::new(),
+            stored: HashMap::new(),
+        };
+        if hashes.is_empty() {
+            return Ok(storage);
+        }
+        for (i, g) in generations[..=until].iter().enumerate() {
+            let db = g.open()?;
+            storage.out_dirs.push(db.read_meta()?.out_dir);
+            for hash in hashes {
+                let chunks = db.select_chunks_for_file(hash)?;
+                if chunks.is_empty() {
+                    continue;
+                }
+                let mut volumes = chunks
+                    .iter()
+                    .filter(|c| c.stored_size() != 0)
+                    .map(|c| c.bak_n)
+                    .collect::<Vec<_>>();
+                volumes.sort();
+                volumes.dedup();
+                storage.stored.entry(*hash).or_default().push((i, volumes));
+            }
+        }
+        Ok(storage)
+    }
+
+    /// The latest of the scanned generations up to `until` storing `hash`. `None` if not
+    /// found, e.g. for an empty file.
+    pub fn stored_in(&self, hash: &Hash, until: usize) -> Option<Stored> {
+        let (i, volumes) = self.stored.get(hash)?.iter().rev().find(|x| x.0 <= until)?;
+        Some(Stored {
+            generation: *i,
+            out_dir: self.out_dirs[*i].clone(),
+            volumes: volumes.clone(),
+        })
+    }
+}
+
+pub struct HistoryEntry {
+    /// Index into the generations
+    pub generation: usize,
+    pub status: VersionStatus,
+    /// `None` if removed
+    pub row: Option<IndexRow>,
+    /// This generation or the latest earlier one storing the content. `None` if not found in
+    /// the state directory, e.g. for an empty file.
+    pub stored_in: Option<Stored>,
+}
+
+/// Keys `path` may have in `db`: an absolute path is split at the root holding it, while a
+/// relative one is looked up under every root.
+fn lookup_keys(db: &IndexDb, path: &Path) -> anyhow::Result<Vec<(u32, PathBuf)>> {
+    let roots = db.select_roots()?;
+    if path.is_absolute() {
+        return Ok(roots
+            .into_iter()
+            .filter_map(|(id, root)| Some((id, path.strip_prefix(root).ok()?.to_path_buf())))
+            .collect());
+    }
+    if roots.is_empty() {
+        // sets made before roots were recorded have the single root 0
+        return Ok(vec![(0, path.into())]);
+    }
+    Ok(roots
+        .into_iter()
+        .map(|(id, _)| (id, path.to_path_buf()))
+        .collect())
+}
+
+fn find_row(db: &IndexDb, path: &Path) -> anyhow::Result<Option<IndexRow>> {
+    for (root, relative) in lookup_keys(db, path)? {
+        if let Some(x) = db.select_index_by_path(root, &relative)? {
+            return Ok(Some(x));
+        }
+    }
+    Ok(None)
+}
+
+/// Generations before `generations[first_kept..]` that those still need, as pairs of the
+/// needed one and the one needing it, newest first.
+///
+/// A generation needs the latest earlier one storing each content it references but doesn't
+/// store itself; `restore --at` and `history` read that content from its volumes. A
+/// generation kept for this needs earlier ones in turn.
+pub fn referenced_generations(
+    generations: &[Generation],
+    first_kept: usize,
+) -> anyhow::Result<Vec<(usize, usize)>> {
+    // contents not stored by the kept generations, with the first one referencing them
+    let mut needed = HashMap::<Hash, usize>::new();
+    let mut stored_before = HashSet::new();
+    for (i, g) in generations.iter().enumerate().skip(first_kept) {
+        let db = g.open()?;
+        let stored = db.select_stored_hashes()?;
+        for row in db.select_index_all()? {
+            let hash = Hash(row.hash);
+            // an empty file has no chunks anywhere
+            if row.entry.size != 0 && !stored.contains(&hash) && !stored_before.contains(&hash) {
+                needed.entry(hash).or_insert(i);
+            }
+        }
+        stored_before.extend(stored);
+    }
+
+    let mut referenced = Vec::new();
+    for (i, g) in generations[..first_kept].iter().enumerate().rev() {
+        if needed.is_empty() {
+            break;
+        }
+        let db = g.open()?;
+        let stored = db.select_stored_hashes()?;
+        let Some(by) = stored.iter().filter_map(|x| needed.remove(x)).max() else {
+            continue;
+        };
+        referenced.push((i, by));
+        for row in db.select_index_all()? {
+            let hash = Hash(row.hash);
+            if row.entry.size != 0 && !stored.contains(&hash) {
+                needed.entry(hash).or_insert(i);
+            }
+        }
+    }
+    Ok(referenced)
+}
+
+/// Every generation `path` appears in, plus those it disappeared in.
+pub fn path_history(generations: &[Generation], path: &Path) -> anyhow::Result<Vec<HistoryEntry>> {
+    let mut entries = Vec::new();
+    let mut last: Option<IndexRow> = None;
+    for (i, g) in generations.iter().enumerate() {
+        let row = find_row(&g.open()?, path)?;
+        let status = match (&last, &row) {
+            (None, None) => continue,
+            (Some(_), None) => VersionStatus::Removed,
+            (None, Some(_)) => VersionStatus::Added,
+            (Some(a), Some(b)) if a.hash != b.hash => VersionStatus::Modified,
+            (Some(a), Some(b)) if a.entry.mtime != b.entry.mtime => VersionStatus::Touched,
+            (Some(_), Some(_)) => VersionStatus::Unchanged,
+        };
+        entries.push(HistoryEntry {
+            generation: i,
+            status,
+            row: row.clone(),
+            stored_in: None,
+        });
+        last = row;
+    }
+
+    let Some(until) = entries.last().map(|x| x.generation) else {
+        return Ok(entries);
+    };
+    let hashes = entries
+        .iter()
+        .filter_map(|x| Some(Hash(x.row.as_ref()?.hash)))
+        .collect();
+    let storage = Storage::scan(generations, until, &hashes)?;
+    for x in &mut entries {
+        if let Some(row) = &x.row {
+            x.stored_in = storage.stored_in(&Hash(row.hash), x.generation);
+        }
+    }
+    Ok(entries)
+}
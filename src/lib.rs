@@ -1,17 +1,16 @@
-#![feature(decl_macro)]
+#![feature(yeet_expr)]
 
+use anyhow::anyhow;
 use blake3::Hasher;
-use bytesize::ByteSize;
 use cfg_if::cfg_if;
 use chrono::Local;
-use clap::Parser;
 use colored::Colorize;
 use fern::colors::{Color, ColoredLevelConfig};
 use filetime::FileTime;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use lazy_regex::{regex, Regex};
-use log::error;
+use log::{error, info};
 use once_cell::sync::Lazy;
-use rusqlite::fallible_iterator::{FallibleIterator, IteratorExt};
 use std::ffi::OsString;
 use std::fmt::{Display, Formatter};
 use std::fs::{File, Permissions};
@@ -19,25 +18,34 @@ use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::process::{ChildStdin, Command, Stdio};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
 use std::str::FromStr;
-use std::sync::Mutex;
 use std::thread::{spawn, JoinHandle};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::SystemTime;
 use std::{fs, io};
+use xattrs::Xattrs;
+use yeet_ops::yeet;
 
+pub mod backup;
+pub mod catalog;
+pub mod config;
 pub mod db;
-
-pub macro mutex_lock($e:expr) {
-    $e.lock().unwrap()
-}
-
-pub static ARGS: Lazy<Mutex<CliArgs>> = Lazy::new(|| Mutex::new(Default::default()));
+pub mod diff;
+pub mod find;
+pub mod history;
+pub mod reindex;
+pub mod restore;
+pub mod sparse;
+pub mod verify;
+pub mod volume;
+pub mod xattrs;
 
 const USER_DIR_NAME: &str = ".baktool";
 
 static INDEX_DB_FORMAT: &Lazy<Regex> = regex!("^index_[0-9]{8}_[0-9]{6}$");
 
+pub(crate) const APP_NAME: &str = "backup-tool";
+
 pub fn create_user_dir(src_base: impl AsRef<Path>) -> io::Result<PathBuf> {
     let path = src_base.as_ref().join(USER_DIR_NAME);
     if !path.exists() {
@@ -46,11 +54,124 @@ pub fn create_user_dir(src_base: impl AsRef<Path>) -> io::Result<PathBuf> {
     Ok(path)
 }
 
+/// Default state directory of a set of source roots:
+/// `<data dir>/backup-tool/<names>-<paths hash>`.
+///
+/// The data directory follows `$XDG_DATA_HOME` on Linux.
+pub fn default_state_dir(src_roots: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let Some(data_dir) = dirs::data_dir() else {
+        yeet!(anyhow!("Cannot determine the user data directory"));
+    };
+    let mut names = Vec::new();
+    // paths are separated by a zero byte, so a single root hashes its path alone
+    let mut hasher = Hasher::new();
+    for (i, x) in src_roots.iter().enumerate() {
+        let x = fs::canonicalize(x)?;
+        if i != 0 {
+            hasher.update(&[0]);
+        }
+        hasher.update(&PathBytes::from(&x));
+        names.push(
+            x.file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default()
+                .replace(
+                    |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '.',
+                    "_",
+                ),
+        );
+    }
+    let path_hash = hasher.finalize();
+    let key = format!(
+        "{}-{}",
+        names.join("+"),
+        hex::encode(&path_hash.as_bytes()[..8])
+    );
+    Ok(data_dir.join(APP_NAME).join(key))
+}
+
+/// All state directories under the default location, i.e. those of every set of source
+/// directories backed up without `--state-dir`.
+pub fn default_state_dirs() -> anyhow::Result<Vec<PathBuf>> {
+    let Some(data_dir) = dirs::data_dir() else {
+        yeet!(anyhow!("Cannot determine the user data directory"));
+    };
+    let base = data_dir.join(APP_NAME);
+    if !base.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut state_dirs = fs::read_dir(base)?
+        .map(|x| Ok(x?.path()))
+        .filter(|x: &io::Result<PathBuf>| x.as_ref().map_or(true, |x| x.is_dir()))
+        .collect::<io::Result<Vec<_>>>()?;
+    state_dirs.sort();
+    Ok(state_dirs)
+}
+
+/// Resolves the directory holding index databases of backups of `source_dirs`, without
+/// creating it.
+///
+/// `state_dir` is an explicitly chosen one; `state_in_source` picks the in-source directory
+/// used by older versions.
+pub fn resolve_state_dir(
+    source_dirs: &[PathBuf],
+    state_dir: Option<&Path>,
+    state_in_source: bool,
+) -> anyhow::Result<PathBuf> {
+    if state_in_source {
+        if state_dir.is_some() {
+            yeet!(anyhow!(
+                "`--state-in-source` and `--state-dir` can't be used together"
+            ));
+        }
+        let [root] = source_dirs else {
+            yeet!(anyhow!(
+                "`--state-in-source` needs a single source directory"
+            ));
+        };
+        return Ok(root.join(USER_DIR_NAME));
+    }
+    match state_dir {
+        Some(x) => Ok(x.to_path_buf()),
+        None => default_state_dir(source_dirs),
+    }
+}
+
+/// Like [`resolve_state_dir`], and creates the directory.
+pub fn create_state_dir(
+    source_dirs: &[PathBuf],
+    state_dir: Option<&Path>,
+    state_in_source: bool,
+) -> anyhow::Result<PathBuf> {
+    let path = resolve_state_dir(source_dirs, state_dir, state_in_source)?;
+    if state_in_source {
+        return Ok(create_user_dir(&source_dirs[0])?);
+    }
+    if !path.exists() {
+        fs::create_dir_all(&path)?;
+        // a note for humans to tell which sources this directory belongs to
+        let mut note = Vec::new();
+        for x in source_dirs {
+            note.extend_from_slice(&PathBytes::from(fs::canonicalize(x)?));
+            note.push(b'\n');
+        }
+        fs::write(path.join("source"), note)?;
+    }
+    Ok(path)
+}
+
+/// The in-source state directory used by older versions, if it exists.
+pub fn legacy_user_dir(src_base: impl AsRef<Path>) -> Option<PathBuf> {
+    let path = src_base.as_ref().join(USER_DIR_NAME);
+    path.is_dir().then_some(path)
+}
+
 pub fn index_formatted_name() -> String {
     Local::now().format("index_%Y%m%d_%H%M%S").to_string()
 }
 
-pub fn index_pick_last(base_dir: impl AsRef<Path>) -> anyhow::Result<Option<PathBuf>> {
+/// All index databases in a state directory, oldest first.
+pub fn index_list(base_dir: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
     let mut names = fs::read_dir(base_dir.as_ref())?
         .collect::<Result<Vec<_>, _>>()?
         .into_iter()
@@ -58,46 +179,15 @@ pub fn index_pick_last(base_dir: impl AsRef<Path>) -> anyhow::Result<Option<Path
         .filter(|x| INDEX_DB_FORMAT.is_match(x))
         .collect::<Vec<_>>();
     names.sort();
-    Ok(names.last().map(|x| base_dir.as_ref().join(x)))
-}
-
-pub static CHUNK_SIZE: Lazy<u64> = Lazy::new(|| {
-    ByteSize::from_str(&mutex_lock!(ARGS).chunk_size)
-        .expect("Failed to parse size string")
-        .0
-});
-
-pub static BACKUP_SIZE: Lazy<u64> = Lazy::new(|| {
-    let backup_size = ByteSize::from_str(&mutex_lock!(ARGS).backup_size)
-        .expect("Failed to parse size string")
-        .0;
-    if backup_size < *CHUNK_SIZE {
-        panic!("backup size > chunk size must require")
-    }
-    backup_size
-});
-
-#[derive(Parser, Default, Debug, Clone)]
-pub struct CliArgs {
-    /// Source directory to back up
-    pub source_dir: PathBuf,
-    /// Output directory location
-    #[arg(short, long)]
-    pub out_dir: PathBuf,
-    /// Chunk size for each file. Default to 128MiB
-    #[arg(short, long, default_value = "128MiB")]
-    pub chunk_size: String,
-    /// Size of each backup output file. Default to 3GiB
-    #[arg(short = 's', long, default_value = "3GiB")]
-    pub backup_size: String,
-    /// External program filter for backup files.
-    ///
-    /// E.g. for compression & encryption, `bash -c 'pbzip2 | openssl enc -aes-256-cbc -pbkdf2'` can be used.
-    #[arg(short = 'f', long, allow_hyphen_values = true, num_args = 1..)]
-    pub backup_output_filter: Option<Vec<OsString>>,
-}
-
-pub fn configure_log() -> anyhow::Result<()> {
+    Ok(names.iter().map(|x| base_dir.as_ref().join(x)).collect())
+}
+
+pub fn index_pick_last(base_dir: impl AsRef<Path>) -> anyhow::Result<Option<PathBuf>> {
+    Ok(index_list(base_dir)?.pop())
+}
+
+/// `quiet` leaves only warnings and errors.
+pub fn configure_log(quiet: bool) -> anyhow::Result<()> {
     let colors = ColoredLevelConfig::new()
         // use builder methods
         .info(Color::Green);
@@ -111,7 +201,11 @@ pub fn configure_log() -> anyhow::Result<()> {
                 message
             ))
         })
-        .level(log::LevelFilter::Debug)
+        .level(if quiet {
+            log::LevelFilter::Warn
+        } else {
+            log::LevelFilter::Debug
+        })
         .chain(io::stderr())
         .apply()?;
     Ok(())
@@ -123,11 +217,90 @@ pub struct FileEntry {
     pub path: PathBuf,
     pub size: u64,
     pub mtime: FileNanoTime,
+    /// `None` if not recorded, e.g. in indexes made by older versions
+    pub attrs: Option<FileAttrs>,
+    /// Set only if the file has more than one hard link
+    pub hard_link: Option<InodeId>,
+    /// `None` if not recorded
+    pub xattrs: Option<Xattrs>,
+    /// Index of the source root `path` is relative to
+    pub root: u32,
 }
 
 impl FileEntry {
-    pub fn full_path(&self) -> PathBuf {
-        mutex_lock!(ARGS).source_dir.join(&self.path)
+    /// `source_dirs` are the source roots, by root id.
+    pub fn full_path(&self, source_dirs: &[PathBuf]) -> PathBuf {
+        source_dirs[self.root as usize].join(&self.path)
+    }
+}
+
+/// Identity of a file on the source system; paths sharing it are hard links of each other.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct InodeId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl InodeId {
+    /// Returns `None` for files with a single link.
+    pub fn hard_link_of(metadata: &fs::Metadata) -> Option<Self> {
+        cfg_if! {
+            if #[cfg(unix)] {
+                use std::os::unix::fs::MetadataExt;
+                let (dev, ino) = file_identity(metadata);
+                (metadata.nlink() > 1).then_some(Self { dev, ino })
+            } else {
+                let _ = metadata;
+                None
+            }
+        }
+    }
+}
+
+/// POSIX metadata of a file besides its size and mtime.
+#[derive(Clone, Debug)]
+pub struct FileAttrs {
+    /// Permission bits, including setuid, setgid and sticky bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: FileNanoTime,
+    /// Only recorded; it cannot be set on restore
+    pub ctime: FileNanoTime,
+    /// Birth time, only recorded; not every file system provides it
+    pub btime: Option<FileNanoTime>,
+}
+
+impl FileAttrs {
+    /// Returns `None` on platforms without POSIX metadata.
+    pub fn from_metadata(metadata: &fs::Metadata) -> Option<Self> {
+        cfg_if! {
+            if #[cfg(unix)] {
+                use std::os::unix::fs::MetadataExt;
+                let btime = metadata
+                    .created()
+                    .ok()
+                    .map(|x| FileTime::from_system_time(x).into());
+                Some(Self {
+                    mode: metadata.mode() & 0o7777,
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                    atime: FileTime::from_last_access_time(metadata).into(),
+                    ctime: FileTime::from_unix_time(metadata.ctime(), metadata.ctime_nsec() as u32)
+                        .into(),
+                    btime,
+                })
+            } else {
+                let _ = metadata;
+                None
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn permissions(&self) -> Permissions {
+        use std::os::unix::fs::PermissionsExt;
+        Permissions::from_mode(self.mode)
     }
 }
 
@@ -135,7 +308,10 @@ pub struct ChunkInfo {
     pub hash: Hash,
     pub bak_n: i32,
     pub offset: u64,
+    /// Size in the file, holes included
     pub size: u64,
+    /// Holes of a sparse file in the chunk, relative to its start; only the rest is stored
+    pub holes: Vec<Range>,
 }
 
 pub struct SplitInfo {
@@ -154,6 +330,15 @@ impl From<FileTime> for FileNanoTime {
     }
 }
 
+impl From<FileNanoTime> for FileTime {
+    fn from(value: FileNanoTime) -> Self {
+        FileTime::from_unix_time(
+            (value.0 / 1_000_000_000) as i64,
+            (value.0 % 1_000_000_000) as u32,
+        )
+    }
+}
+
 impl Deref for FileNanoTime {
     type Target = u64;
 
@@ -162,36 +347,424 @@ impl Deref for FileNanoTime {
     }
 }
 
-pub fn index_files(dir: impl AsRef<Path>) -> io::Result<Vec<FileEntry>> {
+/// Kind of a file-system entry recorded as metadata only.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NodeKind {
+    Symlink,
+    Dir,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    /// Recorded for completeness; a socket can't be recreated without its server
+    Socket,
+}
+
+impl NodeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeKind::Symlink => "symlink",
+            NodeKind::Dir => "dir",
+            NodeKind::Fifo => "fifo",
+            NodeKind::CharDevice => "char",
+            NodeKind::BlockDevice => "block",
+            NodeKind::Socket => "socket",
+        }
+    }
+
+    /// Kind of a special file: a FIFO, device node or socket.
+    pub fn special_of(file_type: &fs::FileType) -> Option<Self> {
+        cfg_if! {
+            if #[cfg(unix)] {
+                use std::os::unix::fs::FileTypeExt;
+                Some(if file_type.is_fifo() {
+                    NodeKind::Fifo
+                } else if file_type.is_char_device() {
+                    NodeKind::CharDevice
+                } else if file_type.is_block_device() {
+                    NodeKind::BlockDevice
+                } else if file_type.is_socket() {
+                    NodeKind::Socket
+                } else {
+                    return None;
+                })
+            } else {
+                let _ = file_type;
+                None
+            }
+        }
+    }
+}
+
+impl FromStr for NodeKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "symlink" => NodeKind::Symlink,
+            "dir" => NodeKind::Dir,
+            "fifo" => NodeKind::Fifo,
+            "char" => NodeKind::CharDevice,
+            "block" => NodeKind::BlockDevice,
+            "socket" => NodeKind::Socket,
+            _ => yeet!(anyhow!("Unknown node kind: {s}")),
+        })
+    }
+}
+
+/// A non-regular file-system entry, which has no content to back up.
+#[derive(Clone, Debug)]
+pub struct NodeEntry {
+    /// Always be relative
+    pub path: PathBuf,
+    pub kind: NodeKind,
+    pub mtime: FileNanoTime,
+    pub attrs: Option<FileAttrs>,
+    /// Target of a symlink, as is
+    pub link_target: Option<PathBuf>,
+    /// Device number of a device node
+    pub rdev: Option<u64>,
+    /// `None` if not recorded
+    pub xattrs: Option<Xattrs>,
+    /// Index of the source root `path` is relative to
+    pub root: u32,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct IndexOptions {
+    /// Index the targets of symlinks instead of the links themselves
+    pub follow_symlinks: bool,
+    /// Record extended attributes, which include POSIX ACLs
+    pub xattrs: bool,
+    /// Fail on the first entry that can't be indexed, instead of skipping it
+    pub strict: bool,
+    /// Don't descend into directories on other file systems than the source
+    pub one_file_system: bool,
+    /// Mount points to descend into despite `one_file_system`
+    pub include_mounts: Vec<PathBuf>,
+    /// Entries to leave out, matched against paths relative to the source root; see
+    /// [`exclude_set`]
+    pub excludes: GlobSet,
+}
+
+/// Builds the exclusion set of [`IndexOptions`] from glob patterns. `*` also matches `/`,
+/// so `*.tmp` excludes such files at any depth.
+pub fn exclude_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for x in patterns {
+        builder.add(Glob::new(x).map_err(|e| anyhow!("Invalid exclude pattern: {e}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+#[derive(Default, Debug)]
+pub struct SourceIndex {
+    /// Regular files
+    pub files: Vec<FileEntry>,
+    pub nodes: Vec<NodeEntry>,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// An entry left out of a backup because it couldn't be indexed or read.
+#[derive(Clone, Debug)]
+pub struct SkippedEntry {
+    /// Relative if possible
+    pub path: PathBuf,
+    pub reason: String,
+    /// RFC 3339
+    pub time: String,
+    /// Index of the source root `path` is relative to
+    pub root: u32,
+}
+
+impl SkippedEntry {
+    pub fn new(root: u32, path: PathBuf, reason: impl Display) -> Self {
+        Self {
+            path,
+            reason: reason.to_string(),
+            time: Local::now().to_rfc3339(),
+            root,
+        }
+    }
+}
+
+impl SourceIndex {
+    /// Records `path` as skipped, or fails in strict mode.
+    fn skip(
+        &mut self,
+        path: &Path,
+        base_dir: &Path,
+        root: u32,
+        reason: impl Display,
+        options: &IndexOptions,
+    ) -> io::Result<()> {
+        if options.strict {
+            return Err(io::Error::other(format!("{}: {reason}", path.display())));
+        }
+        error!("Skipped: {}: {reason}", path.display());
+        let path = pathdiff::diff_paths(path, base_dir).unwrap_or(path.into());
+        self.skipped.push(SkippedEntry::new(root, path, reason));
+        Ok(())
+    }
+}
+
+/// Identities (device, inode) of the directories from the root to the one being read.
+type WalkAncestors = Vec<(u64, u64)>;
+
+/// `(read dir state, dir entry state)`; the entry state marks a directory symlink loop.
+type WalkState = (WalkAncestors, bool);
+
+/// Devices the walk may enter in one-file-system mode: the source's own and those of the
+/// explicitly included mount points. `None` means no limit.
+fn allowed_devices(base_dir: &Path, options: &IndexOptions) -> io::Result<Option<Vec<u64>>> {
+    if !options.one_file_system {
+        return Ok(None);
+    }
+    cfg_if! {
+        if #[cfg(unix)] {
+            let mut devices = vec![file_identity(&fs::metadata(base_dir)?).0];
+            for x in &options.include_mounts {
+                let m = fs::metadata(x).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Mount point {}: {e}", x.display()))
+                })?;
+                devices.push(file_identity(&m).0);
+            }
+            Ok(Some(devices))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+fn symlink_node(
+    path: &Path,
+    base_dir: &Path,
+    root: u32,
+    options: &IndexOptions,
+) -> io::Result<NodeEntry> {
+    let metadata = path.symlink_metadata()?;
+    if !metadata.is_symlink() {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    Ok(NodeEntry {
+        path: pathdiff::diff_paths(path, base_dir).expect("Unexpected: cannot get a relative path"),
+        kind: NodeKind::Symlink,
+        mtime: FileTime::from_last_modification_time(&metadata).into(),
+        attrs: FileAttrs::from_metadata(&metadata),
+        link_target: Some(fs::read_link(path)?),
+        rdev: None,
+        xattrs: read_xattrs(path, false, options),
+        root,
+    })
+}
+
+fn device_number(metadata: &fs::Metadata, kind: NodeKind) -> Option<u64> {
+    if !matches!(kind, NodeKind::CharDevice | NodeKind::BlockDevice) {
+        return None;
+    }
+    cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+            Some(metadata.rdev())
+        } else {
+            let _ = metadata;
+            None
+        }
+    }
+}
+
+fn read_xattrs(path: &Path, follow: bool, options: &IndexOptions) -> Option<Xattrs> {
+    if !options.xattrs {
+        return None;
+    }
+    Xattrs::read(path, follow)
+        .inspect_err(|e| {
+            error!(
+                "Failed to read extended attributes: {}: {e}",
+                path.display()
+            )
+        })
+        .ok()
+}
+
+/// Indexes the source root `dir`, whose index is `root`.
+pub fn index_files(
+    dir: impl AsRef<Path>,
+    root: u32,
+    options: &IndexOptions,
+) -> io::Result<SourceIndex> {
     let base_dir = dir.as_ref();
-    let mut collected = Vec::new();
-    let walk = jwalk::WalkDir::new(base_dir).skip_hidden(false);
+    let mut collected = SourceIndex::default();
+    let follow_symlinks = options.follow_symlinks;
+    let allowed_devices = allowed_devices(base_dir, options)?;
+    let excludes = options.excludes.clone();
+    let walk_root = base_dir.to_path_buf();
+    let walk = jwalk::WalkDirGeneric::<WalkState>::new(base_dir)
+        .skip_hidden(false)
+        .follow_links(follow_symlinks)
+        .process_read_dir(move |_, path, ancestors, children| {
+            if !excludes.is_empty() {
+                children.retain(|x| {
+                    let Ok(e) = x else {
+                        return true;
+                    };
+                    let path = e.path();
+                    let relative = path.strip_prefix(&walk_root).unwrap_or(&path);
+                    !excludes.is_match(relative)
+                });
+            }
+            // only following symlinks can form loops, and mount points only matter in
+            // one-file-system mode
+            if !follow_symlinks && allowed_devices.is_none() {
+                return;
+            }
+            cfg_if! {
+                if #[cfg(unix)] {
+                    if follow_symlinks {
+                        if let Ok(m) = fs::metadata(path) {
+                            ancestors.push(file_identity(&m));
+                        }
+                    }
+                    for e in children.iter_mut().flatten() {
+                        if !e.file_type.is_dir() {
+                            continue;
+                        }
+                        let Ok(m) = fs::metadata(e.path()) else {
+                            continue;
+                        };
+                        let (dev, ino) = file_identity(&m);
+                        if follow_symlinks && ancestors.contains(&(dev, ino)) {
+                            e.read_children_path = None;
+                            e.client_state = true;
+                            continue;
+                        }
+                        if allowed_devices.as_ref().is_some_and(|x| !x.contains(&dev)) {
+                            // the mount point itself is still recorded, like `tar` does
+                            info!("Not crossing file system boundary: {}", e.path().display());
+                            e.read_children_path = None;
+                        }
+                    }
+                } else {
+                    let _ = (path, ancestors, children);
+                }
+            }
+        });
     for x in walk {
         let e = match x {
             Ok(e) => e,
             Err(e) => {
-                error!("Error indexing file: {:?}", e);
+                // a broken symlink can't be followed; keep the link itself
+                if let Some(node) = e
+                    .path()
+                    .filter(|_| follow_symlinks)
+                    .and_then(|x| symlink_node(x, base_dir, root, options).ok())
+                {
+                    collected.nodes.push(node);
+                    continue;
+                }
+                let path = e.path().map(|x| x.to_path_buf()).unwrap_or_default();
+                collected.skip(&path, base_dir, root, e, options)?;
                 continue;
             }
         };
+        if e.client_state {
+            error!("Symlink loop, not descending: {}", e.path().display());
+            // keep the looping link itself
+            match symlink_node(&e.path(), base_dir, root, options) {
+                Ok(x) => collected.nodes.push(x),
+                Err(err) => collected.skip(&e.path(), base_dir, root, err, options)?,
+            }
+            continue;
+        }
+        let relative_path = pathdiff::diff_paths(e.path(), base_dir)
+            .expect("Unexpected: cannot get a relative path");
+        if e.file_type.is_symlink() {
+            match symlink_node(&e.path(), base_dir, root, options) {
+                Ok(x) => collected.nodes.push(x),
+                Err(err) => collected.skip(&e.path(), base_dir, root, err, options)?,
+            }
+            continue;
+        }
+        if e.file_type.is_dir() {
+            // the directory itself is kept; only its content is missing
+            if let Some(err) = &e.read_children_error {
+                collected.skip(&e.path(), base_dir, root, err, options)?;
+            }
+            // the root directory itself has an empty path
+            let metadata = match e.metadata() {
+                Ok(x) => x,
+                Err(err) => {
+                    collected.skip(&e.path(), base_dir, root, err, options)?;
+                    continue;
+                }
+            };
+            collected.nodes.push(NodeEntry {
+                path: relative_path,
+                kind: NodeKind::Dir,
+                mtime: FileTime::from_last_modification_time(&metadata).into(),
+                attrs: FileAttrs::from_metadata(&metadata),
+                link_target: None,
+                rdev: None,
+                xattrs: read_xattrs(&e.path(), follow_symlinks, options),
+                root,
+            });
+            continue;
+        }
+        if let Some(kind) = NodeKind::special_of(&e.file_type) {
+            // may be gone since its directory was read
+            let metadata = match e.metadata() {
+                Ok(x) => x,
+                Err(err) => {
+                    collected.skip(&e.path(), base_dir, root, err, options)?;
+                    continue;
+                }
+            };
+            if kind == NodeKind::Socket {
+                info!("Socket recorded as metadata only: {}", e.path().display());
+            }
+            collected.nodes.push(NodeEntry {
+                path: relative_path,
+                kind,
+                mtime: FileTime::from_last_modification_time(&metadata).into(),
+                attrs: FileAttrs::from_metadata(&metadata),
+                link_target: None,
+                rdev: device_number(&metadata, kind),
+                xattrs: read_xattrs(&e.path(), follow_symlinks, options),
+                root,
+            });
+            continue;
+        }
         // only accept regular files
         if !e.file_type.is_file() {
             continue;
         }
-        if File::open(e.path()).is_err() {
-            error!("Failed to open file: {}", e.path().display());
+        if let Err(err) = File::open(e.path()) {
+            collected.skip(&e.path(), base_dir, root, err, options)?;
             continue;
         }
-        let metadata = e.metadata()?;
+        let metadata = match e.metadata() {
+            Ok(x) => x,
+            Err(err) => {
+                collected.skip(&e.path(), base_dir, root, err, options)?;
+                continue;
+            }
+        };
         let mtime = FileTime::from_last_modification_time(&metadata);
-        let relative_path = pathdiff::diff_paths(e.path(), base_dir)
-            .expect("Unexpected: cannot get a relative path");
         let entry = FileEntry {
             path: relative_path,
             size: metadata.len(),
             mtime: mtime.into(),
+            attrs: FileAttrs::from_metadata(&metadata),
+            hard_link: InodeId::hard_link_of(&metadata),
+            xattrs: read_xattrs(&e.path(), follow_symlinks, options),
+            root,
         };
-        collected.push(entry);
+        collected.files.push(entry);
     }
     Ok(collected)
 }
@@ -217,22 +790,25 @@ pub fn read_to_get_hash(
     Ok(hasher.finalize().into())
 }
 
-pub fn file_hash_all_and_chunks(f: impl AsRef<Path>) -> io::Result<(Hash, Option<Vec<Hash>>)> {
+pub fn file_hash_all_and_chunks(
+    f: impl AsRef<Path>,
+    chunk_size: u64,
+) -> io::Result<(Hash, Option<Vec<Hash>>)> {
     let path = f.as_ref();
     let metadata = path.metadata()?;
     let size = metadata.len();
-    if size <= *CHUNK_SIZE {
+    if size <= chunk_size {
         // file is not chunked
         return Ok((compute_file_hash(path)?, None));
     }
     let reader = BufReader::new(File::open(path)?);
     let mut reader_wrapper = HashReadWrapper::new(reader);
     let mut chunks_hash = Vec::new();
-    let n = size / *CHUNK_SIZE;
-    let r = size % *CHUNK_SIZE;
+    let n = size / chunk_size;
+    let r = size % chunk_size;
     // read n chunks
     for _ in 0..n {
-        let hash = read_to_get_hash(&mut reader_wrapper, Some(*CHUNK_SIZE))?;
+        let hash = read_to_get_hash(&mut reader_wrapper, Some(chunk_size))?;
         chunks_hash.push(hash);
     }
     // ... and the probable remaining
@@ -276,9 +852,8 @@ impl<R: Read + Seek> Seek for HashReadWrapper<R> {
     }
 }
 
-pub fn chunks_ranges(file_size: u64) -> Vec<Range> {
+pub fn chunks_ranges(file_size: u64, chunk_size: u64) -> Vec<Range> {
     let mut ranges = Vec::new();
-    let chunk_size = *CHUNK_SIZE;
     let n = file_size / chunk_size;
     let r = file_size % chunk_size;
     for i in 0..n {
@@ -296,15 +871,19 @@ pub fn chunks_ranges(file_size: u64) -> Vec<Range> {
     ranges
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Range {
     pub start: u64,
     pub size: u64,
 }
 
 /// Half of a 32-byte hash is enough.
-const HASH_SIZE: usize = 16;
+pub const HASH_SIZE: usize = 16;
+
+/// Name of the hash recorded in index databases.
+pub const HASH_ALGORITHM: &str = "blake3-128";
 
-#[derive(Default, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, Hash, Eq, PartialEq)]
 pub struct Hash(pub [u8; HASH_SIZE]);
 
 impl Deref for Hash {
@@ -472,3 +1051,61 @@ impl<W: Write + Send + 'static> Write for BakOutputWriter<W> {
         }
     }
 }
+
+/// Reads back a 'bak' file, reversing the output filter by an external program if given.
+pub enum BakInputReader {
+    Plain(BufReader<File>),
+    Filtered(Child, BufReader<ChildStdout>),
+}
+
+impl BakInputReader {
+    pub fn open(path: impl AsRef<Path>, filter: Option<&Vec<OsString>>) -> io::Result<Self> {
+        let file = File::open(path.as_ref())?;
+        let Some(cmd) = filter else {
+            return Ok(Self::Plain(BufReader::new(file)));
+        };
+        let mut child = Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .stderr(Stdio::inherit())
+            .stdin(file)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Self::Filtered(child, BufReader::new(stdout)))
+    }
+}
+
+impl BakInputReader {
+    /// Skips `n` bytes of the (filtered) stream.
+    pub fn skip(&mut self, n: u64) -> io::Result<()> {
+        match self {
+            BakInputReader::Plain(x) => x.seek_relative(n as i64),
+            BakInputReader::Filtered(_, x) => {
+                let skipped = io::copy(&mut x.take(n), &mut io::sink())?;
+                if skipped != n {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for BakInputReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BakInputReader::Plain(x) => x.read(buf),
+            BakInputReader::Filtered(_, x) => x.read(buf),
+        }
+    }
+}
+
+impl Drop for BakInputReader {
+    fn drop(&mut self) {
+        if let BakInputReader::Filtered(child, _) = self {
+            // the stream may not be read to the end
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
@@ -1,264 +1,1041 @@
 #![feature(yeet_expr)]
 
 use anyhow::anyhow;
-use backup_tool::db::{IndexDb, IndexRow};
+use backup_tool::backup::{
+    BackupConfig, BackupJob, BaseIndex, DEFAULT_BACKUP_SIZE, DEFAULT_CHUNK_SIZE,
+};
+use backup_tool::catalog::{
+    catalog_entries, format_time, root_paths, sort_entries, write_catalog, write_entries,
+    write_export, write_tree, ExportFormat, ListFormat, Report, SortKey,
+};
+use backup_tool::config::{default_config_path, media_size, Config, Profile};
+use backup_tool::db::{IndexDb, IndexMeta};
+use backup_tool::diff::{compare, display_key, index_files_of, scanned_files, Change};
+use backup_tool::find::{find, FindQuery, PathPattern};
+use backup_tool::history::{
+    list_generations, parse_start_time, parse_time, path_history, referenced_generations,
+    select_generation, Stored, VersionStatus,
+};
+use backup_tool::reindex::reindex;
+use backup_tool::restore::{restore, RestoreOptions};
+use backup_tool::verify::verify;
 use backup_tool::{
-    chunks_ranges, compute_file_hash, configure_log, create_user_dir, index_files,
-    index_formatted_name, index_pick_last, mutex_lock, BakOutputWriter, ChunkInfo, CliArgs,
-    FileEntry, Hash, HashReadWrapper, SplitInfo, ARGS, BACKUP_SIZE,
+    configure_log, create_state_dir, default_state_dir, default_state_dirs, exclude_set,
+    index_files, resolve_state_dir, FileNanoTime, Hash, IndexOptions, SourceIndex, HASH_ALGORITHM,
 };
-use clap::Parser;
-use log::info;
-use std::collections::{HashMap, HashSet};
+use bytesize::ByteSize;
+use chrono::{Local, NaiveDateTime};
+use clap::{Parser, Subcommand};
+use globset::GlobBuilder;
+use lazy_regex::Regex;
+use log::{info, warn};
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::{fs, io};
 use yeet_ops::yeet;
 
+/// Differential backup tool for cloud and optical media
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Options shared by all commands.
+#[derive(clap::Args, Debug)]
+struct GlobalArgs {
+    /// Directory to keep index databases in.
+    ///
+    /// Default to a directory under `$XDG_DATA_HOME/backup-tool`, keyed by the source paths.
+    #[arg(long, global = true)]
+    state_dir: Option<PathBuf>,
+    /// Configuration file with backup profiles.
+    ///
+    /// Default to `$XDG_CONFIG_HOME/backup-tool/config.toml`.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Only log warnings and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create the state directory of a set of source directories
+    Init(InitArgs),
+    /// Back up source directories; differential if an earlier index exists
+    Backup(BackupArgs),
+    /// Restore files from a backup set
+    Restore(RestoreArgs),
+    /// Check the 'bak' volumes of a backup set against its index
+    Verify(VerifyArgs),
+    /// Rebuild an index database from the 'bak' volumes of a backup set
+    Reindex(ReindexArgs),
+    /// List the files of an index
+    List(ListArgs),
+    /// Compare the files of two indexes
+    Diff(DiffArgs),
+    /// Write the file list of an index as CSV, JSON or NUL-delimited paths
+    Export(ExportArgs),
+    /// Show the versions of a file across the index databases of the state directory
+    History(HistoryArgs),
+    /// Search the files of every index database by path, size, mtime or content
+    Find(FindArgs),
+    /// Delete old index databases from the state directory, except those still needed by the
+    /// kept ones
+    Prune(PruneArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct InitArgs {
+    /// Source directories to be backed up together
+    #[arg(required = true)]
+    source_dirs: Vec<PathBuf>,
+    /// Keep index databases in `<source_dir>/.baktool`, like older versions did
+    #[arg(long)]
+    state_in_source: bool,
+}
+
+/// Options of the `backup` command.
+#[derive(clap::Args, Default, Debug, Clone)]
+struct BackupArgs {
+    /// Source directories to back up, each one a root of the backup set
+    #[arg(required_unless_present = "profile")]
+    source_dirs: Vec<PathBuf>,
+    /// Profile of the config file to take options from; the other flags override it
+    #[arg(short, long)]
+    profile: Option<String>,
+    /// Output directory location.
+    ///
+    /// Default to a new directory in the first existing destination of the profile.
+    #[arg(short, long)]
+    out_dir: Option<PathBuf>,
+    /// Chunk size for each file. Default to 128MiB
+    #[arg(short, long)]
+    chunk_size: Option<String>,
+    /// Size of each backup output file. Default to 3GiB
+    #[arg(short = 's', long)]
+    backup_size: Option<String>,
+    /// Size backup output files for optical media: cd, dvd, dvd-dl, bd25, bd50 or bd100
+    #[arg(long, conflicts_with = "backup_size")]
+    media: Option<String>,
+    /// External program filter for backup files.
+    ///
+    /// E.g. for compression & encryption, `bash -c 'pbzip2 | openssl enc -aes-256-cbc -pbkdf2'` can be used.
+    #[arg(short = 'f', long, allow_hyphen_values = true, num_args = 1..)]
+    backup_output_filter: Option<Vec<OsString>>,
+    /// Keep index databases in `<source_dir>/.baktool`, like older versions did; needs a
+    /// single source directory
+    #[arg(long)]
+    state_in_source: bool,
+    /// Index database to base a differential backup on, instead of the last one in the state
+    /// directory.
+    ///
+    /// E.g. the `index.db` copied into the output directory of the previous backup.
+    #[arg(short = 'b', long)]
+    base_index: Option<PathBuf>,
+    #[command(flatten)]
+    index: IndexArgs,
+    /// Write the `index.txt` and `diff.txt` catalog into the output directory
+    #[arg(long, overrides_with = "no_catalog")]
+    catalog: bool,
+    /// Don't write the catalog, even if the profile does
+    #[arg(long)]
+    no_catalog: bool,
+    /// Print the files, bytes and volumes the backup would write, without writing anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Hash files in a dry run to leave out contents already stored, as a real run does;
+    /// otherwise files are only matched by path, size and mtime
+    #[arg(long, requires = "dry_run")]
+    hash: bool,
+}
+
+/// Options for scanning source directories, shared by `backup` and `diff --live`.
+#[derive(clap::Args, Default, Debug, Clone)]
+struct IndexArgs {
+    /// Glob pattern of entries to leave out, relative to the source directory; may be
+    /// repeated, and replaces the excludes of the profile
+    #[arg(short, long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Back up the targets of symlinks instead of the links themselves
+    #[arg(short = 'L', long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+    /// Back up symlinks as links, even if the profile follows them
+    #[arg(long)]
+    no_follow_symlinks: bool,
+    /// Record extended attributes, including POSIX ACLs and SELinux labels
+    #[arg(long, overrides_with = "no_xattrs")]
+    xattrs: bool,
+    /// Don't record extended attributes, even if the profile does
+    #[arg(long)]
+    no_xattrs: bool,
+    /// Abort instead of skipping files that can't be indexed or read
+    #[arg(long, overrides_with = "no_strict")]
+    strict: bool,
+    /// Skip unreadable files, even if the profile is strict
+    #[arg(long)]
+    no_strict: bool,
+    /// Stay on the file system of the source directory; mount points are recorded but not
+    /// descended into
+    #[arg(short = 'x', long, overrides_with = "no_one_file_system")]
+    one_file_system: bool,
+    /// Cross file system boundaries, even if the profile doesn't
+    #[arg(long)]
+    no_one_file_system: bool,
+    /// Mount point to back up despite `--one-file-system`; may be repeated
+    #[arg(long, value_name = "PATH")]
+    include_mount: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RestoreArgs {
+    /// Output directory of a backup, holding `index.db` and the 'bak' files, then the
+    /// directory to restore files into. Only the latter with `--at`
+    #[arg(value_name = "[BACKUP_DIR] DEST_DIR", required = true, num_args = 1..=2)]
+    dirs: Vec<PathBuf>,
+    /// Restore the generation of the state directory with this name, or the latest one made
+    /// by this local time, e.g. `2026-03-01` or `2026-03-01 12:00`.
+    ///
+    /// Contents stored by earlier backups are read from their recorded output directories.
+    #[arg(long, value_name = "WHEN")]
+    at: Option<String>,
+    /// Source directory whose state directory to use with `--at`; may be repeated, once per
+    /// source. Not needed with `--state-dir`
+    #[arg(long, requires = "at")]
+    source: Vec<PathBuf>,
+    /// Profile of the config file whose state directory to use with `--at`
+    #[arg(short, long, requires = "at")]
+    profile: Option<String>,
+    /// Output directory of an earlier backup in the differential chain to read contents
+    /// from; may be repeated, newest first. Searched after those found with `--at`
+    #[arg(long, value_name = "DIR")]
+    chain: Vec<PathBuf>,
+    /// Do not restore file owners and groups; for restoring without root
+    #[arg(long)]
+    no_owner: bool,
+    /// Do not restore extended attributes and ACLs
+    #[arg(long)]
+    no_xattrs: bool,
+    /// External program to decode the 'bak' files; the reverse of the backup output filter.
+    #[arg(short = 'f', long, allow_hyphen_values = true, num_args = 1..)]
+    input_filter: Option<Vec<OsString>>,
+    /// Restore a source root somewhere else: `<ROOT>=<DEST>`, e.g. `/etc=/mnt/etc`; may be
+    /// repeated
+    #[arg(long = "map", value_name = "ROOT=DEST", value_parser = parse_root_dest)]
+    root_dests: Vec<(PathBuf, PathBuf)>,
+}
+
+fn parse_root_dest(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (root, dest) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<ROOT>=<DEST>`: {s}"))?;
+    Ok((root.into(), dest.into()))
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Output directory of a backup, holding `index.db` and the 'bak' files
+    backup_dir: PathBuf,
+    /// External program to decode the 'bak' files; the reverse of the backup output filter.
+    #[arg(short = 'f', long, allow_hyphen_values = true, num_args = 1..)]
+    input_filter: Option<Vec<OsString>>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReindexArgs {
+    /// 'bak' volumes of one backup set
+    #[arg(required = true)]
+    volumes: Vec<PathBuf>,
+    /// Path of the index database to create
+    #[arg(short, long)]
+    output: PathBuf,
+    /// External program to decode the volumes; the reverse of the backup output filter.
+    ///
+    /// E.g. `bash -c 'openssl enc -d -aes-256-cbc -pbkdf2 | pbzip2 -d'`.
+    #[arg(short = 'f', long, allow_hyphen_values = true, num_args = 1..)]
+    input_filter: Option<Vec<OsString>>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    /// Index database, or a backup output directory holding `index.db`
+    index: PathBuf,
+    /// Only list files under this path
+    #[arg(short, long)]
+    prefix: Option<PathBuf>,
+    /// Show files as an indented tree of directories
+    #[arg(short, long, conflicts_with_all = ["format", "sort", "reverse"])]
+    tree: bool,
+    /// Order of the listed files
+    #[arg(long, value_enum, default_value_t)]
+    sort: SortKey,
+    /// Reverse the sort order
+    #[arg(short, long)]
+    reverse: bool,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t)]
+    format: ListFormat,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Older index database, or a backup output directory holding `index.db`
+    old: PathBuf,
+    /// Newer index database, or a backup output directory holding `index.db`
+    #[arg(required_unless_present = "live")]
+    new: Option<PathBuf>,
+    /// Compare with the source directories as they are now, instead of a newer index
+    #[arg(long, conflicts_with = "new")]
+    live: bool,
+    /// Source directory to scan with `--live`; may be repeated, in root order. Default to the
+    /// roots recorded in the old index
+    #[arg(long, requires = "live")]
+    source: Vec<PathBuf>,
+    /// Hash scanned files whose size or mtime changed, like a differential backup does, to
+    /// tell content changes and moves apart from touched files
+    #[arg(long, requires = "live")]
+    hash: bool,
+    /// Profile of the config file to scan with, as `backup` does with it; the other flags
+    /// override it
+    #[arg(short, long, requires = "live")]
+    profile: Option<String>,
+    /// Scanning options for `--live`, as given to `backup`
+    #[command(flatten)]
+    index: IndexArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExportArgs {
+    /// Index database, or a backup output directory holding `index.db`
+    index: PathBuf,
+    /// Files to export
+    #[arg(short, long, value_enum, default_value_t)]
+    report: Report,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t)]
+    format: ExportFormat,
+    /// File to write to instead of the standard output
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Write both reports as `index.txt` and `diff.txt` CSV files into this directory
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["report", "format", "output"])]
+    catalog: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// Path of the file, relative to its source directory or absolute
+    path: PathBuf,
+    /// Source directory whose state directory to search; may be repeated, once per source.
+    /// Not needed with `--state-dir`
+    #[arg(long)]
+    source: Vec<PathBuf>,
+    /// Profile of the config file whose state directory to search
+    #[arg(short, long)]
+    profile: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct FindArgs {
+    /// Glob matched against the file name, e.g. `invoice_*.pdf`
+    #[arg(long, value_name = "GLOB", conflicts_with_all = ["path", "regex"])]
+    name: Option<String>,
+    /// Glob matched against the whole path, as listed by `list`
+    #[arg(long, value_name = "GLOB", conflicts_with = "regex")]
+    path: Option<String>,
+    /// Regular expression searched for in the path
+    #[arg(long)]
+    regex: Option<String>,
+    /// Match globs regardless of case
+    #[arg(short, long)]
+    ignore_case: bool,
+    #[arg(long)]
+    min_size: Option<ByteSize>,
+    #[arg(long)]
+    max_size: Option<ByteSize>,
+    /// Modified at or after this local time; a date alone means its start
+    #[arg(long, value_name = "WHEN")]
+    after: Option<String>,
+    /// Modified at or before this local time; a date alone means its end
+    #[arg(long, value_name = "WHEN")]
+    before: Option<String>,
+    /// Content hash, as printed by `list`
+    #[arg(long)]
+    hash: Option<String>,
+    /// Source directory whose state directory to search; may be repeated, once per source.
+    /// Not needed with `--state-dir`
+    #[arg(long)]
+    source: Vec<PathBuf>,
+    /// Profile of the config file whose state directory to search
+    #[arg(short, long)]
+    profile: Option<String>,
+    /// Search every state directory under the default location
+    #[arg(long, conflicts_with_all = ["source", "profile"])]
+    all: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct PruneArgs {
+    /// Source directories whose state directory to prune; not needed with `--state-dir`
+    source_dirs: Vec<PathBuf>,
+    /// Profile of the config file whose state directory to prune
+    #[arg(short, long)]
+    profile: Option<String>,
+    /// Number of the newest index databases to keep; the latest one is always kept, as the
+    /// base of the next backup.
+    ///
+    /// An older one is kept too while its volumes store content that a kept one references
+    /// without storing it, as with differential backups: `restore --at` and `history` find
+    /// that content through it.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    keep: u64,
+}
+
 fn main() -> anyhow::Result<()> {
-    let args = CliArgs::parse();
-    *mutex_lock!(ARGS) = args.clone();
-    configure_log()?;
+    let cli = Cli::parse();
+    configure_log(cli.global.quiet)?;
+    let global = &cli.global;
+    match cli.command {
+        Command::Init(args) => init(global, args),
+        Command::Backup(args) => backup(global, args),
+        Command::Restore(args) => restore_command(global, args),
+        Command::Verify(args) => verify_command(args),
+        Command::Reindex(args) => reindex_command(args),
+        Command::List(args) => list(args),
+        Command::Diff(args) => diff(global, args),
+        Command::Export(args) => export(args),
+        Command::History(args) => history(global, args),
+        Command::Find(args) => find_command(global, args),
+        Command::Prune(args) => prune(global, args),
+    }
+}
+
+fn init(global: &GlobalArgs, args: InitArgs) -> anyhow::Result<()> {
+    let state_dir = create_state_dir(
+        &args.source_dirs,
+        global.state_dir.as_deref(),
+        args.state_in_source,
+    )?;
+    println!("{}", state_dir.display());
+    Ok(())
+}
+
+fn backup(global: &GlobalArgs, args: BackupArgs) -> anyhow::Result<()> {
+    let profile = load_profile(global, args.profile.as_deref())?;
+    let source_dirs = match args.source_dirs {
+        x if !x.is_empty() => x,
+        _ if !profile.source_dirs.is_empty() => profile.source_dirs.clone(),
+        _ => yeet!(anyhow!("No source directory given, neither in the profile")),
+    };
+    let out_dir = match args.out_dir {
+        Some(x) => x,
+        // not written to, and the destination media may not be inserted yet
+        None if args.dry_run => PathBuf::new(),
+        None if !profile.destinations.is_empty() => profile
+            .pick_destination()?
+            .join(Local::now().format("backup_%Y%m%d_%H%M%S").to_string()),
+        None => yeet!(anyhow!("No output directory given, neither in the profile")),
+    };
+    let parse_size = |x: &str| {
+        ByteSize::from_str(x)
+            .map(|x| x.0)
+            .map_err(|e| anyhow!("Invalid size `{x}`: {e}"))
+    };
+    let chunk_size = match args.chunk_size.as_ref().or(profile.chunk_size.as_ref()) {
+        Some(x) => parse_size(x)?,
+        None => DEFAULT_CHUNK_SIZE,
+    };
+    let backup_size = match (&args.backup_size, &args.media) {
+        (Some(x), _) => parse_size(x)?,
+        (_, Some(x)) => media_size(x)?,
+        _ => match (&profile.backup_size, &profile.media) {
+            (Some(x), _) => parse_size(x)?,
+            (_, Some(x)) => media_size(x)?,
+            _ => DEFAULT_BACKUP_SIZE,
+        },
+    };
+    let output_filter = args.backup_output_filter.or_else(|| {
+        profile
+            .filter
+            .as_ref()
+            .map(|x| x.iter().map(OsString::from).collect())
+    });
+    let index_options = index_options(&args.index, &profile)?;
+
+    let state_dir = global.state_dir.as_deref().or(profile.state_dir.as_deref());
+    let state_dir = if args.dry_run {
+        resolve_state_dir(&source_dirs, state_dir, args.state_in_source)?
+    } else {
+        create_state_dir(&source_dirs, state_dir, args.state_in_source)?
+    };
+    info!("State directory: {}", state_dir.display());
 
-    if !args.out_dir.exists() {
-        fs::create_dir_all(&args.out_dir)?;
+    let mut config = BackupConfig::new(source_dirs, out_dir, state_dir);
+    if let Some(x) = args.base_index {
+        config.base_index = BaseIndex::Path(x);
     }
-    let child_count = fs::read_dir(&args.out_dir)?.count();
-    if child_count != 0 {
+    config.chunk_size = chunk_size;
+    config.backup_size = backup_size;
+    config.output_filter = output_filter;
+    config.index_options = index_options;
+    config.write_catalog = flag(args.catalog, args.no_catalog, profile.catalog);
+    let job = BackupJob::new(config)?;
+    if args.dry_run {
+        return print_plan(&job, args.hash);
+    }
+    let report = job.run()?;
+
+    if !report.skipped.is_empty() {
+        for x in &report.skipped {
+            warn!("Not backed up: {}: {}", x.path.display(), x.reason);
+        }
         yeet!(anyhow!(
-            "Non-empty output directory; please choose another one."
+            "{} entries were skipped; they are listed in the `skipped` table of the index",
+            report.skipped.len()
         ));
     }
+    Ok(())
+}
 
-    let user_dir = create_user_dir(&args.source_dir)?;
-    let last_index = index_pick_last(create_user_dir(args.source_dir)?)?;
-    let ctx = Context {
-        index_db: user_dir.join(index_formatted_name()),
-        last_index,
+/// The profile named `name` of the config file, or an empty one.
+fn load_profile(global: &GlobalArgs, name: Option<&str>) -> anyhow::Result<Profile> {
+    let Some(name) = name else {
+        return Ok(Profile::default());
+    };
+    let Some(path) = global.config.clone().or_else(default_config_path) else {
+        yeet!(anyhow!("Cannot determine the config file location"));
     };
+    Ok(Config::load(&path)?.profile(name)?.clone())
+}
 
-    match &ctx.last_index {
-        None => {
-            info!("Processing initial backup...");
-            initial_backup(&ctx)?
-        }
-        Some(_) => {
-            info!("Processing differential backup...");
-            differential_backup(&ctx)?
-        }
+/// Scanning options from the flags, or else from the profile.
+fn index_options(args: &IndexArgs, profile: &Profile) -> anyhow::Result<IndexOptions> {
+    let excludes = match &args.exclude[..] {
+        [] => &profile.excludes[..],
+        x => x,
+    };
+    let include_mounts = match &args.include_mount[..] {
+        [] => &profile.include_mounts[..],
+        x => x,
+    };
+    let one_file_system = flag(
+        args.one_file_system,
+        args.no_one_file_system,
+        profile.one_file_system,
+    );
+    if !include_mounts.is_empty() && !one_file_system {
+        yeet!(anyhow!("Included mount points need `--one-file-system`"));
     }
+    Ok(IndexOptions {
+        follow_symlinks: flag(
+            args.follow_symlinks,
+            args.no_follow_symlinks,
+            profile.follow_symlinks,
+        ),
+        xattrs: flag(args.xattrs, args.no_xattrs, profile.xattrs),
+        strict: flag(args.strict, args.no_strict, profile.strict),
+        one_file_system,
+        include_mounts: include_mounts.to_vec(),
+        excludes: exclude_set(excludes)?,
+    })
+}
 
-    fs::copy(&ctx.index_db, args.out_dir.join("index.db"))?;
-    Ok(())
+/// A boolean option given by a flag and its `--no-` counterpart, or else by the profile.
+fn flag(on: bool, off: bool, profile: bool) -> bool {
+    on || (!off && profile)
 }
 
-struct Context {
-    index_db: PathBuf,
-    last_index: Option<PathBuf>,
-}
-
-fn differential_backup(ctx: &Context) -> anyhow::Result<()> {
-    // full scan is still needed
-    info!("Indexing files...");
-    let files = index_files(&mutex_lock!(ARGS).source_dir)?;
-    info!("File count: {}", files.len());
-    let out_dir = mutex_lock!(ARGS).out_dir.clone();
-    let ref_db_path = ctx.last_index.clone().unwrap();
-    info!("Picked ref_db: {}", ref_db_path.display());
-    let ref_db = IndexDb::new(ref_db_path, false)?;
-
-    // find out differential files by path, mtime and size
-    info!("Reading old index database...");
-    let old_index = ref_db.select_index_all()?;
-    let mut duplicates: Vec<(&FileEntry, Hash)> = Vec::new();
-    info!("Deduplicating by metadata...");
-    let metadata_map = old_index
-        .iter()
-        .map(|x| ((x.entry.path.as_path(), x.entry.mtime, x.entry.size), x))
-        .collect::<HashMap<_, _>>();
-    let old_index_hash_set = old_index.iter().map(|x| &x.hash).collect::<HashSet<_>>();
-    let mut remaining = Vec::new();
-    for e in &files {
-        if let Some(v) = metadata_map.get(&(e.path.as_path(), e.mtime, e.size)) {
-            duplicates.push((e, Hash(v.hash)));
-        } else {
-            remaining.push(e);
-        }
+fn print_plan(job: &BackupJob, hash: bool) -> anyhow::Result<()> {
+    let plan = job.plan(hash)?;
+    let config = job.config();
+    let mut out = io::stdout().lock();
+    for x in &plan.stored_files {
+        let path = match config.source_dirs.len() {
+            1 => x.path.clone(),
+            _ => x.full_path(&config.source_dirs),
+        };
+        writeln!(out, "{}\t{}", x.size, path.display())?;
     }
-    info!("File count: {}", remaining.len());
-    info!("Deduplicating diff by hash...");
-    // if the diff file hash matches in the old file index, skip its backup
-    let mut files_to_backup = Vec::new();
-    let remaining_count = remaining.len();
-    for (i, e) in remaining.into_iter().enumerate() {
-        info!("Hashing: [{}/{}] {}", i, remaining_count, e.path.display());
-        let file_hash = compute_file_hash(e.full_path())?;
-        if !old_index_hash_set.contains(&&*file_hash) {
-            files_to_backup.push((file_hash, e));
+    writeln!(
+        out,
+        "{} backup: {} of {} files to store{}",
+        if plan.differential {
+            "Differential"
+        } else {
+            "Initial"
+        },
+        plan.stored_files.len(),
+        plan.file_count,
+        if plan.hashed {
+            ""
         } else {
-            duplicates.push((e, file_hash));
+            " (not hashed; contents already stored are counted too)"
         }
+    )?;
+    writeln!(out, "Data: {}", ByteSize(plan.stored_bytes))?;
+    writeln!(
+        out,
+        "Volumes: {} of up to {}, {} in total before any filter",
+        plan.volume_count,
+        ByteSize(config.backup_size),
+        ByteSize(plan.volume_bytes)
+    )?;
+    for x in &plan.skipped {
+        warn!("Would not be backed up: {}: {}", x.path.display(), x.reason);
     }
-    files_to_backup.sort_by(|a, b| a.1.path.cmp(&b.1.path));
-    info!("File count: {}", files_to_backup.len());
-    assert_eq!(duplicates.len() + files_to_backup.len(), files.len());
-
-    info!("Writing to backup files...");
-    let file_splits = write_bak_files(&out_dir, files_to_backup.iter().copied())?;
-
-    info!("Creating index database...");
-    let mut db = IndexDb::new(&ctx.index_db, true)?;
-    let db_tx = db.transaction()?;
-    db_tx.insert_file_split_info(&file_splits)?;
-    let new_files_ref_map = files_to_backup
-        .iter()
-        .map(|x| (x.1 as *const _, x))
-        .collect::<HashMap<_, _>>();
-    // the current index = files_to_backup ...
-    for e in &files {
-        let entry = new_files_ref_map.get(&(e as *const _));
-        if let Some(e) = entry {
-            // this is the new file being backed up
-            let row = IndexRow {
-                hash: *e.0,
-                entry: e.1.clone(),
+    Ok(())
+}
+
+fn restore_command(global: &GlobalArgs, args: RestoreArgs) -> anyhow::Result<()> {
+    let mut chain = Vec::new();
+    let (backup_dir, dest_dir) = match (&args.at, &args.dirs[..]) {
+        (None, [backup_dir, dest_dir]) => (backup_dir.clone(), dest_dir),
+        (Some(at), [dest_dir]) => {
+            let state_dir = existing_state_dir(global, args.profile.as_deref(), &args.source)?;
+            let generations = list_generations(&state_dir)?;
+            let i = select_generation(&generations, at)?;
+            info!("Restoring generation {}", generations[i].name);
+            let Some(backup_dir) = generations[i].out_dir()? else {
+                yeet!(anyhow!(
+                    "{} doesn't record its output directory; please give it instead of `--at`",
+                    generations[i].name
+                ));
             };
-            db_tx.insert_index_row(&row)?;
+            for x in generations[..i].iter().rev() {
+                match x.out_dir()? {
+                    Some(dir) if dir.join("index.db").is_file() => chain.push(dir),
+                    Some(dir) => warn!(
+                        "Output directory of {} not found: {}",
+                        x.name,
+                        dir.display()
+                    ),
+                    None => warn!("{} doesn't record its output directory", x.name),
+                }
+            }
+            (backup_dir, dest_dir)
         }
+        (None, _) => yeet!(anyhow!("Both BACKUP_DIR and DEST_DIR are needed")),
+        (Some(_), _) => yeet!(anyhow!("Only DEST_DIR is needed with `--at`")),
+    };
+    chain.extend(args.chain);
+    let options = RestoreOptions {
+        no_owner: args.no_owner,
+        no_xattrs: args.no_xattrs,
+        input_filter: args.input_filter,
+        root_dests: args.root_dests,
+        chain,
+    };
+    let report = restore(&backup_dir, dest_dir, &options)?;
+    info!(
+        "Restored {} file(s), {} other entries",
+        report.file_count, report.node_count
+    );
+    if !report.failures.is_empty() {
+        yeet!(anyhow!(
+            "{} file(s) failed to restore",
+            report.failures.len()
+        ));
+    }
+    Ok(())
+}
+
+fn verify_command(args: VerifyArgs) -> anyhow::Result<()> {
+    let report = verify(&args.backup_dir, args.input_filter.as_ref())?;
+    info!(
+        "Checked {} chunk(s), {} in {} volume(s)",
+        report.chunk_count,
+        ByteSize(report.byte_count),
+        report.volume_count
+    );
+    if !report.failures.is_empty() {
+        yeet!(anyhow!("{} problem(s) found", report.failures.len()));
+    }
+    Ok(())
+}
+
+fn reindex_command(args: ReindexArgs) -> anyhow::Result<()> {
+    if args.output.exists() {
+        yeet!(anyhow!("Output already exists: {}", args.output.display()));
     }
-    // ... + duplicates
-    for x in duplicates {
-        db_tx.insert_index_row(&IndexRow {
-            entry: x.0.clone(),
-            hash: *x.1,
+    // written aside and renamed when complete, so a failed run leaves no output behind
+    let mut partial = args.output.clone().into_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let result = (|| {
+        let mut db = IndexDb::new(&partial, true)?;
+        db.write_meta(&IndexMeta {
+            tool_version: Some(env!("CARGO_PKG_VERSION").into()),
+            created_at: Some(Local::now().to_rfc3339()),
+            hash_algorithm: Some(HASH_ALGORITHM.into()),
+            // the volumes of a set live in one directory
+            out_dir: fs::canonicalize(&args.volumes[0])
+                .ok()
+                .and_then(|x| x.parent().map(Into::into)),
+            ..Default::default()
         })?;
+        reindex(&args.volumes, args.input_filter.as_ref(), &mut db)
+    })();
+    let report = match result {
+        Ok(x) => x,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            yeet!(e);
+        }
+    };
+    fs::rename(&partial, &args.output)?;
+    info!(
+        "Recovered {} file(s), {} chunk(s) from {} volume(s)",
+        report.file_count, report.chunk_count, report.volume_count
+    );
+    if !report.gaps.is_empty() {
+        yeet!(anyhow!("{} gap(s) found", report.gaps.len()));
+    }
+    Ok(())
+}
+
+/// Opens an index database given either its path or a backup output directory.
+fn open_index(path: &Path) -> anyhow::Result<IndexDb> {
+    let path = if path.is_dir() {
+        path.join("index.db")
+    } else {
+        path.to_path_buf()
+    };
+    IndexDb::open_read_only(path)
+}
+
+fn list(args: ListArgs) -> anyhow::Result<()> {
+    let db = open_index(&args.index)?;
+    let mut entries = catalog_entries(&db, Report::Index)?;
+    if let Some(prefix) = &args.prefix {
+        entries.retain(|x| x.path.starts_with(prefix));
     }
-    // so if I do db_tx.0.commit()? outside, it doesn't work
-    {
-        let tx = db_tx;
-        tx.0.commit()?;
+    let mut out = io::stdout().lock();
+    if args.tree {
+        write_tree(&mut out, &entries)?;
+        return Ok(());
     }
-    assert_eq!(db.query_index_row_count()?, files.len() as u64);
-    assert_eq!(
-        db.query_chunk_row_count()?,
-        file_splits.iter().map(|x| x.chunks.len()).sum::<usize>() as u64
+    sort_entries(&mut entries, args.sort, args.reverse);
+    write_entries(&mut out, &entries, args.format)
+}
+
+fn diff(global: &GlobalArgs, args: DiffArgs) -> anyhow::Result<()> {
+    let old_db = open_index(&args.old)?;
+    let old_roots = root_paths(&old_db)?;
+    let old_files = index_files_of(&old_db)?;
+    let (new_roots, new_files) = match &args.new {
+        Some(x) => {
+            let new_db = open_index(x)?;
+            (root_paths(&new_db)?, index_files_of(&new_db)?)
+        }
+        None => {
+            let source_dirs = match &args.source[..] {
+                [] => {
+                    let roots = old_db.select_roots()?;
+                    if roots.is_empty() {
+                        yeet!(anyhow!(
+                            "The old index records no source directory; please give `--source`"
+                        ));
+                    }
+                    roots.into_iter().map(|x| x.1).collect()
+                }
+                x => x.to_vec(),
+            };
+            // scanned like a backup with the same options, so the changes are what the next
+            // differential backup would see
+            let profile = load_profile(global, args.profile.as_deref())?;
+            let options = index_options(&args.index, &profile)?;
+            let mut scan = SourceIndex::default();
+            for (root, dir) in source_dirs.iter().enumerate() {
+                let mut x = index_files(dir, root as u32, &options)?;
+                scan.files.append(&mut x.files);
+            }
+            let files = scanned_files(&scan, &source_dirs, &old_files, args.hash);
+            (old_roots.clone(), files)
+        }
+    };
+
+    let changes = compare(&old_files, &new_files);
+    let mut out = io::stdout().lock();
+    let (mut added, mut removed, mut modified, mut moved) = (0, 0, 0, 0);
+    for x in &changes {
+        let delta = x.size_delta();
+        match x {
+            Change::Added { key, .. } => {
+                added += 1;
+                let path = display_key(&new_roots, key);
+                writeln!(out, "+ {}\t{delta:+}", path.display())?;
+            }
+            Change::Removed { key, .. } => {
+                removed += 1;
+                let path = display_key(&old_roots, key);
+                writeln!(out, "- {}\t{delta:+}", path.display())?;
+            }
+            Change::Modified { key, .. } => {
+                modified += 1;
+                let path = display_key(&new_roots, key);
+                writeln!(out, "M {}\t{delta:+}", path.display())?;
+            }
+            Change::Moved { from, to, .. } => {
+                moved += 1;
+                let from = display_key(&old_roots, from);
+                let to = display_key(&new_roots, to);
+                writeln!(out, "R {} -> {}", from.display(), to.display())?;
+            }
+        }
+    }
+    let total_delta = changes.iter().map(|x| x.size_delta()).sum::<i64>();
+    info!(
+        "{added} added, {removed} removed, {modified} modified, {moved} moved; {}{}",
+        if total_delta < 0 { "-" } else { "+" },
+        ByteSize(total_delta.unsigned_abs())
     );
+    Ok(())
+}
 
+fn export(args: ExportArgs) -> anyhow::Result<()> {
+    let db = open_index(&args.index)?;
+    if let Some(dir) = &args.catalog {
+        fs::create_dir_all(dir)?;
+        return write_catalog(&db, dir);
+    }
+    let entries = catalog_entries(&db, args.report)?;
+    match &args.output {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            write_export(&mut out, &entries, args.format)?;
+            out.flush()?;
+        }
+        None => write_export(&mut io::stdout().lock(), &entries, args.format)?,
+    }
     Ok(())
 }
 
-fn initial_backup(ctx: &Context) -> anyhow::Result<()> {
-    // Do the first full backup
-    info!("Indexing files...");
-    let src_dir = mutex_lock!(ARGS).source_dir.clone();
-    let files = index_files(&src_dir)?;
-    let out_dir = mutex_lock!(ARGS).out_dir.clone();
-    let file_count = files.len();
-    info!("File count: {file_count}");
-
-    info!("Deduplicating by hash. Please wait...");
-    let mut file_hash_list = Vec::new();
-    let mut unique_list = HashMap::new();
-    for (i, e) in files.iter().enumerate() {
-        info!("Hashing: [{}/{}] {}", i, file_count, e.path.display());
-        let hash = compute_file_hash(e.full_path())?;
-        unique_list.insert(hash, e);
-        file_hash_list.push(hash);
+/// An existing state directory, resolved as `backup` does: the one given by `--state-dir`
+/// or the profile, or else that of `source_dirs` or the profile's source directories.
+fn existing_state_dir(
+    global: &GlobalArgs,
+    profile: Option<&str>,
+    source_dirs: &[PathBuf],
+) -> anyhow::Result<PathBuf> {
+    let profile = load_profile(global, profile)?;
+    let source_dirs = match source_dirs {
+        [] => &profile.source_dirs[..],
+        x => x,
+    };
+    let state_dir = global.state_dir.as_ref().or(profile.state_dir.as_ref());
+    let state_dir = match (state_dir, source_dirs) {
+        (Some(x), _) => x.clone(),
+        (None, []) => yeet!(anyhow!(
+            "Either source directories, a profile or `--state-dir` is needed"
+        )),
+        (None, x) => default_state_dir(x)?,
+    };
+    if !state_dir.is_dir() {
+        yeet!(anyhow!(
+            "State directory not found: {}",
+            state_dir.display()
+        ));
+    }
+    Ok(state_dir)
+}
+
+fn history(global: &GlobalArgs, args: HistoryArgs) -> anyhow::Result<()> {
+    let state_dir = existing_state_dir(global, args.profile.as_deref(), &args.source)?;
+    let generations = list_generations(&state_dir)?;
+    let entries = path_history(&generations, &args.path)?;
+    if entries.is_empty() {
+        yeet!(anyhow!(
+            "{} is in none of the {} index database(s)",
+            args.path.display(),
+            generations.len()
+        ));
     }
-    info!("Writing to backup files...");
-
-    let mut files_to_backup = unique_list.iter().map(|x| (*x.0, *x.1)).collect::<Vec<_>>();
-    files_to_backup.sort_by(|a, b| a.1.path.cmp(&b.1.path));
-    let file_splits = write_bak_files(&out_dir, files_to_backup.into_iter())?;
-
-    info!("Creating index database...");
-    let mut db = IndexDb::new(&ctx.index_db, true)?;
-    let db_tx = db.transaction()?;
-    for x in files.iter().zip(file_hash_list) {
-        db_tx.insert_index_row(&IndexRow {
-            hash: *x.1,
-            entry: x.0.clone(),
-        })?;
+    let mut out = io::stdout().lock();
+    for x in entries {
+        let generation = &generations[x.generation].name;
+        let status = match x.status {
+            VersionStatus::Added => "added",
+            VersionStatus::Modified => "modified",
+            VersionStatus::Touched => "touched",
+            VersionStatus::Unchanged => "unchanged",
+            VersionStatus::Removed => "removed",
+        };
+        let Some(row) = x.row else {
+            writeln!(out, "{generation}\t{status}")?;
+            continue;
+        };
+        let stored_in = match x.stored_in {
+            Some(Stored {
+                generation,
+                out_dir: Some(dir),
+                ..
+            }) => format!("{} ({})", generations[generation].name, dir.display()),
+            Some(x) => generations[x.generation].name.clone(),
+            None => "-".into(),
+        };
+        writeln!(
+            out,
+            "{generation}\t{status}\t{}\t{}\t{}\t{stored_in}",
+            row.entry.size,
+            format_time(row.entry.mtime),
+            Hash(row.hash)
+        )?;
     }
-    db_tx.insert_file_split_info(&file_splits)?;
-    db_tx.0.commit()?;
-    info!("Done");
     Ok(())
 }
 
-fn write_bak_files<'a>(
-    out_dir: &Path,
-    files: impl ExactSizeIterator<Item = (Hash, &'a FileEntry)>,
-) -> anyhow::Result<Vec<SplitInfo>> {
-    let file_count = files.len();
-    let mut file_chunks_hash = vec![Vec::<Hash>::new(); file_count];
-
-    let mut bak_n = 0;
-    let mut bak_total_size = 0_u64;
-    // chunk offset of the current 'bak' file in index.txt
-    let mut chunk_offset = 0_u64;
-    let output_filter = mutex_lock!(ARGS).backup_output_filter.clone();
-    let create_bak_file = |bak_n: i32| -> anyhow::Result<_> {
-        let bak_file = out_dir.join(format!("bak{bak_n}"));
-        let writer = BufWriter::new(File::create(&bak_file)?);
-        let writer = BakOutputWriter::new(writer, output_filter.as_ref())?;
-        Ok(writer)
+fn find_query(args: &FindArgs) -> anyhow::Result<FindQuery> {
+    let glob = |x: &str| {
+        GlobBuilder::new(x)
+            .case_insensitive(args.ignore_case)
+            .build()
+            .map(|x| x.compile_matcher())
+            .map_err(|e| anyhow!("Invalid glob `{x}`: {e}"))
     };
-    let mut bak_output = create_bak_file(bak_n)?;
-
-    let mut split_info_list = Vec::new();
-
-    for (i, e) in files.into_iter().enumerate() {
-        let file_size = e.1.size;
-        let file_path = e.1.path.as_path();
-        let file_path_full = e.1.full_path();
-        split_info_list.push(SplitInfo {
-            file_hash: e.0,
-            chunks: Default::default(),
-        });
-
-        let chunks = chunks_ranges(file_size);
-        let mut reader = BufReader::new(File::open(file_path_full)?);
-        for (chunk_n, r) in chunks.iter().enumerate() {
-            info!(
-                "Write file [{i}/{file_count}] {} chunk #{}",
-                file_path.display(),
-                chunk_n + 1
-            );
-
-            // Check if a new 'bak' file is needed, that's, this 'bak' file is not sufficient for
-            // storing a new chunk.
-            // write to the new 'bak' file; close the old and create a new one
-            if bak_total_size + r.size > *BACKUP_SIZE {
-                bak_n += 1;
-                chunk_offset = 0;
-                bak_output.flush()?;
-                // directly assign to it; Rust will drop the old one
-                bak_output = create_bak_file(bak_n)?;
-                bak_total_size = 0;
-            }
+    let pattern = match (&args.name, &args.path, &args.regex) {
+        (Some(x), _, _) => Some(PathPattern::Name(glob(x)?)),
+        (_, Some(x), _) => Some(PathPattern::Path(glob(x)?)),
+        (_, _, Some(x)) => Some(PathPattern::Regex(
+            Regex::new(x).map_err(|e| anyhow!("Invalid regex `{x}`: {e}"))?,
+        )),
+        _ => None,
+    };
+    let time = |x: &str, parse: fn(&str) -> Option<NaiveDateTime>| -> anyhow::Result<_> {
+        let Some(time) = parse(x).and_then(|x| x.and_local_timezone(Local).earliest()) else {
+            yeet!(anyhow!(
+                "Invalid time `{x}`; expected a date like 2026-03-01 or 2026-03-01 12:00"
+            ));
+        };
+        Ok(FileNanoTime(
+            time.timestamp_nanos_opt().unwrap_or_default().max(0) as u64,
+        ))
+    };
+    let hash = match &args.hash {
+        Some(x) => {
+            let mut hash = Hash::default();
+            hex::decode_to_slice(x, &mut hash.0).map_err(|e| anyhow!("Invalid hash `{x}`: {e}"))?;
+            Some(hash)
+        }
+        None => None,
+    };
+    Ok(FindQuery {
+        pattern,
+        min_size: args.min_size.map(|x| x.0),
+        max_size: args.max_size.map(|x| x.0),
+        modified_after: args
+            .after
+            .as_deref()
+            .map(|x| time(x, parse_start_time))
+            .transpose()?,
+        modified_before: args
+            .before
+            .as_deref()
+            .map(|x| time(x, parse_time))
+            .transpose()?,
+        hash,
+    })
+}
 
-            let chunk_reader = reader.by_ref().take(r.size);
-            let mut hash_wrapper = HashReadWrapper::new(chunk_reader);
-            io::copy(&mut hash_wrapper, &mut bak_output)?;
-            let chunk_hash = hash_wrapper.finalize();
-            file_chunks_hash[i].push(chunk_hash);
-
-            split_info_list[i].chunks.push(ChunkInfo {
-                hash: chunk_hash,
-                bak_n,
-                offset: chunk_offset,
-                size: r.size,
-            });
-
-            bak_total_size += r.size;
-            chunk_offset += r.size;
+fn find_command(global: &GlobalArgs, args: FindArgs) -> anyhow::Result<()> {
+    let query = find_query(&args)?;
+    let state_dirs = if args.all {
+        default_state_dirs()?
+    } else {
+        vec![existing_state_dir(
+            global,
+            args.profile.as_deref(),
+            &args.source,
+        )?]
+    };
+    let mut out = io::stdout().lock();
+    let mut generation_count = 0;
+    let mut match_count = 0;
+    for state_dir in &state_dirs {
+        let generations = list_generations(state_dir)?;
+        generation_count += generations.len();
+        // tell the state directories apart when searching several
+        let prefix = match args.all {
+            true => format!(
+                "{}/",
+                state_dir.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            false => String::new(),
+        };
+        for x in find(&generations, &query)? {
+            let first = &generations[x.generations[0]].name;
+            let seen_in = match x.generations.len() {
+                1 => format!("{prefix}{first}"),
+                n => format!(
+                    "{prefix}{first}..{} ({n} generations)",
+                    generations[x.generations[n - 1]].name
+                ),
+            };
+            let stored_in = match &x.stored_in {
+                Some(stored) => {
+                    let volumes = stored
+                        .volumes
+                        .iter()
+                        .map(|n| format!("bak{n}"))
+                        .collect::<Vec<_>>();
+                    let dir = match &stored.out_dir {
+                        Some(x) => format!(" ({})", x.display()),
+                        None => String::new(),
+                    };
+                    let name = &generations[stored.generation].name;
+                    format!("{prefix}{name}{dir}: {}", volumes.join(", "))
+                }
+                None => "-".into(),
+            };
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{seen_in}\t{stored_in}",
+                x.path.display(),
+                x.row.entry.size,
+                format_time(x.row.entry.mtime),
+                Hash(x.row.hash)
+            )?;
+            match_count += 1;
         }
-        debug_assert_eq!(reader.stream_position()?, file_size);
     }
-    // flush the last 'bak' file
-    bak_output.flush()?;
+    if match_count == 0 {
+        yeet!(anyhow!(
+            "No file matches in the {generation_count} index database(s)"
+        ));
+    }
+    info!("{match_count} version(s) found");
+    Ok(())
+}
 
-    Ok(split_info_list)
+fn prune(global: &GlobalArgs, args: PruneArgs) -> anyhow::Result<()> {
+    let state_dir = existing_state_dir(global, args.profile.as_deref(), &args.source_dirs)?;
+    let generations = list_generations(&state_dir)?;
+    let first_kept = generations.len().saturating_sub(args.keep as usize);
+    let referenced = referenced_generations(&generations, first_kept)?;
+    for (i, by) in &referenced {
+        warn!(
+            "Keeping {}: {} references content stored in its volumes",
+            generations[*i].name, generations[*by].name
+        );
+    }
+    let mut remove_count = 0;
+    for (i, x) in generations[..first_kept].iter().enumerate() {
+        if referenced.iter().any(|r| r.0 == i) {
+            continue;
+        }
+        info!("Removing {}", x.path.display());
+        fs::remove_file(&x.path)?;
+        remove_count += 1;
+    }
+    info!(
+        "Removed {remove_count} index database(s), kept {}",
+        generations.len() - remove_count
+    );
+    Ok(())
 }
@@ -0,0 +1,184 @@
+//! Rebuilding an index database from 'bak' volumes.
+
+use crate::db::{ChunkRow, IndexDb, IndexRow};
+use crate::volume::VolumeScanner;
+use crate::{BakInputReader, FileEntry, FileNanoTime, Hash};
+use anyhow::anyhow;
+use lazy_regex::regex_captures;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+#[derive(Default, Debug)]
+pub struct ReindexReport {
+    pub volume_count: usize,
+    pub chunk_count: u64,
+    pub file_count: u64,
+    /// Human-readable descriptions of missing or damaged parts
+    pub gaps: Vec<String>,
+}
+
+struct RecoveredFile {
+    root: u32,
+    path: PathBuf,
+    size: u64,
+    mtime: u64,
+    chunk_count: u32,
+    chunks: BTreeMap<u32, ChunkRow>,
+}
+
+/// Parses `n` out of a volume file name `bak{n}`.
+pub fn parse_bak_n(path: &Path) -> Option<i32> {
+    let name = path.file_name()?.to_str()?;
+    let (_, n) = regex_captures!("^bak([0-9]+)$", name)?;
+    n.parse().ok()
+}
+
+/// Scans all volumes of one backup set and writes the recovered files and chunks into `db`.
+///
+/// Only files whose chunks are all present and intact are indexed; everything else is
+/// reported in [`ReindexReport::gaps`]. Files are recovered under the source roots their
+/// frames record; frames written before roots were recorded all belong to root 0.
+pub fn reindex(
+    volumes: &[PathBuf],
+    input_filter: Option<&Vec<OsString>>,
+    db: &mut IndexDb,
+) -> anyhow::Result<ReindexReport> {
+    let mut volumes = volumes
+        .iter()
+        .map(|x| {
+            parse_bak_n(x)
+                .map(|n| (n, x))
+                .ok_or_else(|| anyhow!("Not a 'bak' volume: {}", x.display()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    volumes.sort_by_key(|x| x.0);
+
+    let mut report = ReindexReport {
+        volume_count: volumes.len(),
+        ..Default::default()
+    };
+    for pair in volumes.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            return Err(anyhow!("Duplicated volume: bak{}", pair[0].0));
+        }
+    }
+    let max_n = volumes.last().map(|x| x.0).unwrap_or(-1);
+    for n in 0..=max_n {
+        if volumes.binary_search_by_key(&n, |x| x.0).is_err() {
+            report.gaps.push(format!("Volume bak{n} is missing"));
+        }
+    }
+
+    let mut files = HashMap::<Hash, RecoveredFile>::new();
+    let mut roots = BTreeMap::<u32, PathBuf>::new();
+    for (bak_n, path) in volumes {
+        info!("Scanning volume: {}", path.display());
+        let mut scanner = VolumeScanner::new(BakInputReader::open(path, input_filter)?);
+        loop {
+            let frame = match scanner.next_frame() {
+                Ok(Some(f)) => f,
+                Ok(None) => break,
+                Err(e) => {
+                    report.gaps.push(format!(
+                        "bak{bak_n}: unreadable frame at offset {}: {e}",
+                        scanner.position()
+                    ));
+                    break;
+                }
+            };
+            let header = frame.header;
+            if frame.stored_hash != frame.actual_hash {
+                report.gaps.push(format!(
+                    "bak{bak_n}: corrupted chunk #{} of {}",
+                    header.chunk_n + 1,
+                    header.path.display()
+                ));
+                continue;
+            }
+            if let Some(root_path) = &header.root_path {
+                let root = roots
+                    .entry(header.root)
+                    .or_insert_with(|| root_path.clone());
+                if root != root_path {
+                    report.gaps.push(format!(
+                        "bak{bak_n}: chunk #{} of {} has root #{} at {}, already seen at {}",
+                        header.chunk_n + 1,
+                        header.path.display(),
+                        header.root,
+                        root_path.display(),
+                        root.display()
+                    ));
+                    continue;
+                }
+            }
+            report.chunk_count += 1;
+            let file = files
+                .entry(header.file_hash)
+                .or_insert_with(|| RecoveredFile {
+                    root: header.root,
+                    path: header.path.clone(),
+                    size: header.file_size,
+                    mtime: header.mtime,
+                    chunk_count: header.chunk_count,
+                    chunks: Default::default(),
+                });
+            file.chunks.insert(
+                header.chunk_n,
+                ChunkRow {
+                    file_hash: *header.file_hash,
+                    chunk_n: header.chunk_n,
+                    chunk_hash: *frame.actual_hash,
+                    bak_n,
+                    offset: frame.offset,
+                    size: header.chunk_size,
+                    holes: header.holes.clone(),
+                },
+            );
+        }
+    }
+
+    let mut files = files.into_iter().collect::<Vec<_>>();
+    files.sort_by(|a, b| (a.1.root, &a.1.path).cmp(&(b.1.root, &b.1.path)));
+    let db_tx = db.transaction()?;
+    for (id, path) in &roots {
+        db_tx.insert_root_row(*id, path)?;
+    }
+    for (hash, file) in files {
+        let missing = (0..file.chunk_count)
+            .filter(|x| !file.chunks.contains_key(x))
+            .map(|x| format!("#{}", x + 1))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            report.gaps.push(format!(
+                "{}: missing chunk(s) {}",
+                file.path.display(),
+                missing.join(", ")
+            ));
+            continue;
+        }
+        db_tx.insert_index_row(&IndexRow {
+            entry: FileEntry {
+                path: file.path,
+                size: file.size,
+                mtime: FileNanoTime(file.mtime),
+                attrs: None,
+                hard_link: None,
+                xattrs: None,
+                root: file.root,
+            },
+            hash: *hash,
+        })?;
+        for row in file.chunks.values() {
+            db_tx.insert_chunk_row(row)?;
+        }
+        report.file_count += 1;
+    }
+    db_tx.0.commit()?;
+
+    for gap in &report.gaps {
+        warn!("Gap: {gap}");
+    }
+    Ok(report)
+}
@@ -0,0 +1,584 @@
+//! Restoring files from a backup set.
+
+use crate::db::{IndexDb, IndexRow};
+use crate::sparse::data_extents;
+use crate::xattrs::Xattrs;
+use crate::{
+    BakInputReader, FileAttrs, FileNanoTime, HashReadWrapper, InodeId, NodeEntry, NodeKind, Range,
+    HASH_SIZE,
+};
+use anyhow::anyhow;
+use cfg_if::cfg_if;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::{fs, io};
+use yeet_ops::yeet;
+
+#[derive(Default, Debug, Clone)]
+pub struct RestoreOptions {
+    /// Do not restore owners and groups
+    pub no_owner: bool,
+    /// Do not restore extended attributes and ACLs
+    pub no_xattrs: bool,
+    /// External program to decode the 'bak' files
+    pub input_filter: Option<Vec<OsString>>,
+    /// Destinations of source roots, by their recorded paths, instead of the defaults
+    pub root_dests: Vec<(PathBuf, PathBuf)>,
+    /// Output directories of earlier backups in the differential chain, newest first; file
+    /// contents not stored in the restored set are read from them
+    pub chain: Vec<PathBuf>,
+}
+
+#[derive(Default, Debug)]
+pub struct RestoreReport {
+    pub file_count: u64,
+    /// Non-regular entries, like symlinks
+    pub node_count: u64,
+    /// Files that could not be restored, with reasons
+    pub failures: Vec<String>,
+}
+
+const SPARSE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Metadata that couldn't be restored for lack of privileges, already warned about once
+#[derive(Default)]
+struct Warned {
+    owner: bool,
+    xattrs: bool,
+}
+
+/// A chunk to copy from a 'bak' file into a restored file.
+struct ChunkJob {
+    /// Index of the backup set holding it: the restored one, then the chain
+    set: usize,
+    bak_n: i32,
+    offset: u64,
+    /// Stored size, holes excluded
+    size: u64,
+    chunk_hash: [u8; HASH_SIZE],
+    dest: PathBuf,
+    /// Where the stored data goes in the restored file, in order
+    extents: Vec<Range>,
+}
+
+/// Restores all files of `backup_dir` (an output directory holding `index.db` and
+/// 'bak' files) into `dest_dir`.
+///
+/// Contents missing from `backup_dir` are looked up in [`RestoreOptions::chain`].
+///
+/// A set with a single source root is restored right into `dest_dir`; with more, each root
+/// goes to its own path under `dest_dir`, e.g. `/etc` to `<dest_dir>/etc`. Either can be
+/// overridden by [`RestoreOptions::root_dests`].
+pub fn restore(
+    backup_dir: &Path,
+    dest_dir: &Path,
+    options: &RestoreOptions,
+) -> anyhow::Result<RestoreReport> {
+    let db = IndexDb::open_read_only(backup_dir.join("index.db"))?;
+    let mut sets = vec![(backup_dir.to_path_buf(), None)];
+    for x in &options.chain {
+        sets.push((
+            x.clone(),
+            Some(IndexDb::open_read_only(x.join("index.db"))?),
+        ));
+    }
+    let dests = root_dests(&db, dest_dir, options)?;
+    let dest_of = |root: u32, path: &Path| -> anyhow::Result<PathBuf> {
+        let dir = dests
+            .get(&root)
+            .ok_or_else(|| anyhow!("Unknown source root: {root}"))?;
+        // recorded paths are relative and plain; anything else could escape the destination
+        if !path.components().all(|x| matches!(x, Component::Normal(_))) {
+            yeet!(anyhow!("Unsafe path in the index: {}", path.display()));
+        }
+        Ok(dir.join(path))
+    };
+    let rows = db.select_index_all()?;
+    let (mut dirs, nodes): (Vec<_>, Vec<_>) = db
+        .select_node_all()?
+        .into_iter()
+        .partition(|x| x.kind == NodeKind::Dir);
+    let mut report = RestoreReport::default();
+
+    // a tampered index is refused before anything is written
+    let entries = rows.iter().map(|x| (x.entry.root, &x.entry.path));
+    for (root, path) in entries.chain(dirs.iter().chain(&nodes).map(|x| (x.root, &x.path))) {
+        dest_of(root, path)?;
+    }
+
+    // directories are created first, but get their metadata last
+    for x in &dirs {
+        fs::create_dir_all(dest_of(x.root, &x.path)?)?;
+    }
+
+    // files with the same content are only extracted once
+    let mut groups = HashMap::<[u8; HASH_SIZE], Vec<&IndexRow>>::new();
+    for row in &rows {
+        groups.entry(row.hash).or_default().push(row);
+    }
+
+    let mut jobs = Vec::new();
+    // restored paths of files that could not be restored, left out of the metadata pass
+    let mut failed_dest = HashSet::new();
+    // (extracted file, its entry, other files with the same content)
+    let mut copies = Vec::new();
+    for (hash, group) in &groups {
+        let primary = &group[0].entry;
+        let mut found = None;
+        for (set, (_, set_db)) in sets.iter().enumerate() {
+            let chunks = set_db
+                .as_ref()
+                .unwrap_or(&db)
+                .select_chunks_for_file(hash)?;
+            if chunks.iter().map(|x| x.size).sum::<u64>() == primary.size {
+                found = Some((set, chunks));
+                break;
+            }
+        }
+        let Some((set, chunks)) = found else {
+            for x in group {
+                report.failures.push(format!(
+                    "{}: content is not stored in this backup set or its chain",
+                    x.entry.path.display()
+                ));
+                failed_dest.insert(dest_of(x.entry.root, &x.entry.path)?);
+            }
+            continue;
+        };
+        let dest = dest_of(primary.root, &primary.path)?;
+        create_sized_file(&dest, primary.size)?;
+        let mut file_offset = 0_u64;
+        for c in chunks {
+            // holes are left unwritten in the sized file, keeping it sparse
+            let size = c.stored_size();
+            if size != 0 {
+                let extents = data_extents(c.size, &c.holes)
+                    .into_iter()
+                    .map(|x| Range {
+                        start: file_offset + x.start,
+                        size: x.size,
+                    })
+                    .collect();
+                jobs.push(ChunkJob {
+                    set,
+                    bak_n: c.bak_n,
+                    offset: c.offset,
+                    size,
+                    chunk_hash: c.chunk_hash,
+                    dest: dest.clone(),
+                    extents,
+                });
+            }
+            file_offset += c.size;
+        }
+        copies.push((dest, primary, &group[1..]));
+    }
+
+    jobs.sort_by_key(|x| (x.set, x.bak_n, x.offset));
+    // extracted files that couldn't be completed, with the first reason
+    let mut corrupted = HashMap::<PathBuf, String>::new();
+    let mut fail = |dest: &Path, reason: String| {
+        corrupted.entry(dest.to_path_buf()).or_insert(reason);
+    };
+    for volume_jobs in jobs.chunk_by(|a, b| (a.set, a.bak_n) == (b.set, b.bak_n)) {
+        let bak_n = volume_jobs[0].bak_n;
+        let bak_file = sets[volume_jobs[0].set].0.join(format!("bak{bak_n}"));
+        info!("Reading {}", bak_file.display());
+        let mut reader = match BakInputReader::open(&bak_file, options.input_filter.as_ref()) {
+            Ok(x) => x,
+            Err(e) => {
+                for job in volume_jobs {
+                    fail(
+                        &job.dest,
+                        format!("cannot open {}: {e}", bak_file.display()),
+                    );
+                }
+                continue;
+            }
+        };
+        let mut position = 0_u64;
+        for (i, job) in volume_jobs.iter().enumerate() {
+            let mut dest = match OpenOptions::new().write(true).open(&job.dest) {
+                Ok(x) => x,
+                Err(e) => {
+                    fail(&job.dest, format!("cannot write: {e}"));
+                    continue;
+                }
+            };
+            let copied = reader.skip(job.offset - position).and_then(|()| {
+                let mut hash_reader = HashReadWrapper::new(&mut reader);
+                let mut copied = 0;
+                for x in &job.extents {
+                    dest.seek(SeekFrom::Start(x.start))?;
+                    let n = copy_sparse((&mut hash_reader).take(x.size), &mut dest)?;
+                    copied += n;
+                    if n < x.size {
+                        break;
+                    }
+                }
+                Ok((copied, hash_reader.finalize()))
+            });
+            match copied {
+                Ok((copied, hash)) => {
+                    if copied != job.size || *hash != job.chunk_hash {
+                        fail(&job.dest, "corrupted chunk in the backup".into());
+                    }
+                }
+                Err(e) => {
+                    // the position in the stream is lost, so is the rest of the volume
+                    for job in &volume_jobs[i..] {
+                        fail(
+                            &job.dest,
+                            format!("failed to read {}: {e}", bak_file.display()),
+                        );
+                    }
+                    break;
+                }
+            }
+            position = job.offset + job.size;
+        }
+    }
+
+    // the first restored path of each hard-linked file
+    let mut hard_link_first = HashMap::<InodeId, PathBuf>::new();
+    for (dest, primary, others) in copies {
+        if let Some(reason) = corrupted.get(&dest) {
+            // kept for salvaging, under a name that can't pass for a good restore
+            let mut corrupt_name = dest.clone().into_os_string();
+            corrupt_name.push(".corrupt");
+            fs::rename(&dest, &corrupt_name)?;
+            report.failures.push(format!(
+                "{}: {reason}; partial content left in {}",
+                dest.display(),
+                Path::new(&corrupt_name).display()
+            ));
+            for x in others {
+                let other_dest = dest_of(x.entry.root, &x.entry.path)?;
+                report
+                    .failures
+                    .push(format!("{}: {reason}", other_dest.display()));
+                failed_dest.insert(other_dest);
+            }
+            failed_dest.insert(dest);
+            continue;
+        }
+        if let Some(x) = primary.hard_link {
+            hard_link_first.entry(x).or_insert(dest.clone());
+        }
+        for x in others {
+            let other_dest = dest_of(x.entry.root, &x.entry.path)?;
+            if let Some(parent) = other_dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match x.entry.hard_link.and_then(|l| hard_link_first.get(&l)) {
+                Some(first) => {
+                    if other_dest.symlink_metadata().is_ok() {
+                        fs::remove_file(&other_dest)?;
+                    }
+                    fs::hard_link(first, &other_dest)?;
+                }
+                None => {
+                    fs::copy(&dest, &other_dest)?;
+                    if let Some(l) = x.entry.hard_link {
+                        hard_link_first.insert(l, other_dest);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut warned = Warned::default();
+    for row in &rows {
+        let dest = dest_of(row.entry.root, &row.entry.path)?;
+        if failed_dest.contains(&dest) || !dest.exists() {
+            continue;
+        }
+        let entry = &row.entry;
+        let xattrs = entry.xattrs.as_ref();
+        if let Err(e) = apply_attrs(
+            &dest,
+            entry.attrs.as_ref(),
+            xattrs,
+            entry.mtime,
+            options,
+            &mut warned,
+        ) {
+            report.failures.push(format!(
+                "{}: failed to restore metadata: {e}",
+                row.entry.path.display()
+            ));
+        }
+        report.file_count += 1;
+    }
+
+    for node in nodes {
+        if node.kind == NodeKind::Socket {
+            warn!("Not recreating socket: {}", node.path.display());
+            continue;
+        }
+        let dest = dest_of(node.root, &node.path)?;
+        if let Err(e) = restore_node(&dest, &node, options, &mut warned) {
+            report.failures.push(format!(
+                "{}: failed to restore {}: {e}",
+                node.path.display(),
+                node.kind.as_str()
+            ));
+            continue;
+        }
+        report.node_count += 1;
+    }
+
+    // deepest first, so restoring a directory's mtime isn't undone by changes in its parent
+    // and a read-only directory doesn't block its children
+    dirs.sort_by_key(|x| std::cmp::Reverse(x.path.components().count()));
+    for x in dirs {
+        let dest = dest_of(x.root, &x.path)?;
+        if let Err(e) = apply_attrs(
+            &dest,
+            x.attrs.as_ref(),
+            x.xattrs.as_ref(),
+            x.mtime,
+            options,
+            &mut warned,
+        ) {
+            report.failures.push(format!(
+                "{}: failed to restore metadata: {e}",
+                x.path.display()
+            ));
+            continue;
+        }
+        report.node_count += 1;
+    }
+
+    for x in &report.failures {
+        warn!("{x}");
+    }
+    Ok(report)
+}
+
+/// Destination directory of each source root.
+fn root_dests(
+    db: &IndexDb,
+    dest_dir: &Path,
+    options: &RestoreOptions,
+) -> anyhow::Result<HashMap<u32, PathBuf>> {
+    let roots = db.select_roots()?;
+    let mut dests = HashMap::new();
+    if roots.len() <= 1 {
+        // also covers sets made before roots were recorded
+        dests.insert(roots.first().map_or(0, |x| x.0), dest_dir.to_path_buf());
+    } else {
+        for (id, path) in &roots {
+            let relative = path
+                .components()
+                .filter(|x| matches!(x, Component::Normal(_)))
+                .collect::<PathBuf>();
+            dests.insert(*id, dest_dir.join(relative));
+        }
+    }
+    for (root, dest) in &options.root_dests {
+        let Some((id, _)) = roots.iter().find(|x| x.1 == *root) else {
+            yeet!(anyhow!(
+                "No such source root in the backup: {}",
+                root.display()
+            ));
+        };
+        dests.insert(*id, dest.clone());
+    }
+    Ok(dests)
+}
+
+fn create_sized_file(path: &Path, size: u64) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = File::create(path)?;
+    file.set_len(size)?;
+    Ok(())
+}
+
+/// Copies like [`io::copy`], but seeks over zero blocks instead of writing them. `dest` must
+/// already be sized, so skipped blocks read as zeros.
+fn copy_sparse(mut reader: impl Read, dest: &mut File) -> io::Result<u64> {
+    let mut buf = vec![0_u8; SPARSE_BLOCK_SIZE];
+    let mut copied = 0_u64;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf[..n].iter().all(|&x| x == 0) {
+            dest.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            dest.write_all(&buf[..n])?;
+        }
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// Applies owner, permissions, extended attributes and timestamps. The owner comes first
+/// since `chown` may clear setuid/setgid bits; ACLs come after `chmod`, which would
+/// otherwise overwrite the ACL mask. Timestamps are set even if extended attributes fail.
+fn apply_attrs(
+    path: &Path,
+    attrs: Option<&FileAttrs>,
+    xattrs: Option<&Xattrs>,
+    mtime: FileNanoTime,
+    options: &RestoreOptions,
+    warned: &mut Warned,
+) -> io::Result<()> {
+    let Some(attrs) = attrs else {
+        let xattrs_result = apply_xattrs(path, xattrs, options, warned);
+        filetime::set_file_mtime(path, mtime.into())?;
+        return xattrs_result;
+    };
+    #[cfg(unix)]
+    {
+        if !options.no_owner {
+            lchown_or_warn(path, attrs, warned)?;
+        }
+        fs::set_permissions(path, attrs.permissions())?;
+    }
+    let xattrs_result = apply_xattrs(path, xattrs, options, warned);
+    filetime::set_file_times(path, attrs.atime.into(), mtime.into())?;
+    xattrs_result
+}
+
+fn restore_node(
+    dest: &Path,
+    node: &NodeEntry,
+    options: &RestoreOptions,
+    warned: &mut Warned,
+) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match node.kind {
+        NodeKind::Dir => unreachable!("directories are restored separately"),
+        NodeKind::Symlink => {
+            let target = node
+                .link_target
+                .as_ref()
+                .ok_or(io::ErrorKind::InvalidData)?;
+            if dest.symlink_metadata().is_ok() {
+                fs::remove_file(dest)?;
+            }
+            cfg_if! {
+                if #[cfg(unix)] {
+                    std::os::unix::fs::symlink(target, dest)?;
+                    if let (Some(attrs), false) = (&node.attrs, options.no_owner) {
+                        lchown_or_warn(dest, attrs, warned)?;
+                    }
+                    let xattrs_result = apply_xattrs(dest, node.xattrs.as_ref(), options, warned);
+                    let atime = node.attrs.as_ref().map_or(node.mtime, |x| x.atime);
+                    filetime::set_symlink_file_times(dest, atime.into(), node.mtime.into())?;
+                    xattrs_result?;
+                } else {
+                    let _ = (target, options, warned);
+                    return Err(io::ErrorKind::Unsupported.into());
+                }
+            }
+        }
+        NodeKind::Fifo | NodeKind::CharDevice | NodeKind::BlockDevice => {
+            if dest.symlink_metadata().is_ok() {
+                fs::remove_file(dest)?;
+            }
+            make_special_file(dest, node)?;
+            apply_attrs(
+                dest,
+                node.attrs.as_ref(),
+                node.xattrs.as_ref(),
+                node.mtime,
+                options,
+                warned,
+            )?;
+        }
+        NodeKind::Socket => unreachable!("sockets are not recreated"),
+    }
+    Ok(())
+}
+
+/// Creates a FIFO or device node. Device nodes normally need root.
+fn make_special_file(path: &Path, node: &NodeEntry) -> io::Result<()> {
+    cfg_if! {
+        if #[cfg(unix)] {
+            use std::ffi::CString;
+            use std::os::unix::ffi::OsStrExt;
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            let mode = node.attrs.as_ref().map_or(0o644, |x| x.mode) & 0o7777;
+            let r = match node.kind {
+                NodeKind::Fifo => unsafe { libc::mkfifo(c_path.as_ptr(), mode as libc::mode_t) },
+                NodeKind::CharDevice | NodeKind::BlockDevice => {
+                    let file_type = if node.kind == NodeKind::CharDevice {
+                        libc::S_IFCHR
+                    } else {
+                        libc::S_IFBLK
+                    };
+                    let rdev = node.rdev.ok_or(io::ErrorKind::InvalidData)?;
+                    unsafe {
+                        libc::mknod(
+                            c_path.as_ptr(),
+                            file_type | mode as libc::mode_t,
+                            rdev as libc::dev_t,
+                        )
+                    }
+                }
+                _ => unreachable!(),
+            };
+            if r != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        } else {
+            let _ = (path, node);
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+fn apply_xattrs(
+    path: &Path,
+    xattrs: Option<&Xattrs>,
+    options: &RestoreOptions,
+    warned: &mut Warned,
+) -> io::Result<()> {
+    let Some(x) = xattrs.filter(|_| !options.no_xattrs) else {
+        return Ok(());
+    };
+    match x.apply(path) {
+        // e.g. `trusted.*` and `security.*` attributes need root
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            if !warned.xattrs {
+                warn!(
+                    "Cannot restore some extended attributes (not running as root?); use `--no-xattrs` to skip"
+                );
+                warned.xattrs = true;
+            }
+            Ok(())
+        }
+        r => r,
+    }
+}
+
+#[cfg(unix)]
+fn lchown_or_warn(path: &Path, attrs: &FileAttrs, warned: &mut Warned) -> io::Result<()> {
+    match std::os::unix::fs::lchown(path, Some(attrs.uid), Some(attrs.gid)) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            if !warned.owner {
+                warn!(
+                    "Cannot restore file owners (not running as root?); use `--no-owner` to skip"
+                );
+                warned.owner = true;
+            }
+            Ok(())
+        }
+        r => r,
+    }
+}
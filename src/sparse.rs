@@ -0,0 +1,281 @@
+//! Sparse file support.
+//!
+//! The holes of a file are found chunk by chunk and recorded as extents relative to each
+//! chunk; only the data between them is stored, and restore leaves them unwritten so the
+//! restored file is sparse again. A chunk lying entirely in a hole stores no data at all.
+
+use crate::{read_to_get_hash, Hash, Range};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+/// Size of an encoded hole: start and size as little-endian `u64`s.
+pub const HOLE_ENCODED_SIZE: usize = 16;
+
+/// The holes of `file` in each of `ranges`, relative to the start of the range.
+///
+/// What lies past the current end of the file is not a hole, so a file that shrank reads
+/// short instead of being padded. The file offset is left unspecified; callers should seek
+/// before reading.
+pub fn chunk_holes(file: &File, ranges: &[Range]) -> io::Result<Vec<Vec<Range>>> {
+    let mut holes = vec![Vec::new(); ranges.len()];
+    if !may_be_sparse(file)? {
+        return Ok(holes);
+    }
+    let file_len = file.metadata()?.len();
+    for (holes, r) in holes.iter_mut().zip(ranges) {
+        let end = (r.start + r.size).min(file_len);
+        let mut pos = r.start;
+        while pos < end {
+            let data = next_data(file, pos)?.unwrap_or(end).min(end);
+            if data > pos {
+                holes.push(Range {
+                    start: pos - r.start,
+                    size: data - pos,
+                });
+            }
+            if data == end {
+                break;
+            }
+            pos = next_hole(file, data)?.max(data + 1);
+        }
+    }
+    Ok(holes)
+}
+
+/// The parts of a chunk of `size` bytes between its `holes`, relative to its start.
+pub fn data_extents(size: u64, holes: &[Range]) -> Vec<Range> {
+    let mut extents = Vec::new();
+    let mut pos = 0;
+    for x in holes.iter().chain([&Range {
+        start: size,
+        size: 0,
+    }]) {
+        if x.start > pos {
+            extents.push(Range {
+                start: pos,
+                size: x.start - pos,
+            });
+        }
+        pos = x.start + x.size;
+    }
+    extents
+}
+
+/// Bytes stored for a chunk of `size` bytes with `holes`.
+pub fn stored_size(size: u64, holes: &[Range]) -> u64 {
+    size - holes.iter().map(|x| x.size).sum::<u64>()
+}
+
+/// Layout: `(start: u64, size: u64)*`, little-endian.
+pub fn encode_holes(holes: &[Range]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(holes.len() * HOLE_ENCODED_SIZE);
+    for x in holes {
+        data.extend_from_slice(&x.start.to_le_bytes());
+        data.extend_from_slice(&x.size.to_le_bytes());
+    }
+    data
+}
+
+/// Decodes the holes of a chunk of `size` bytes, which must be sorted, apart and inside it.
+pub fn decode_holes(data: &[u8], size: u64) -> io::Result<Vec<Range>> {
+    if !data.len().is_multiple_of(HOLE_ENCODED_SIZE) {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut holes = Vec::<Range>::with_capacity(data.len() / HOLE_ENCODED_SIZE);
+    for x in data.chunks_exact(HOLE_ENCODED_SIZE) {
+        let hole = Range {
+            start: u64::from_le_bytes(x[..8].try_into().unwrap()),
+            size: u64::from_le_bytes(x[8..].try_into().unwrap()),
+        };
+        let apart = holes.last().is_none_or(|x| hole.start > x.start + x.size);
+        let in_chunk = hole.start.checked_add(hole.size).is_some_and(|x| x <= size);
+        if hole.size == 0 || !apart || !in_chunk {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Bad hole in chunk",
+            ));
+        }
+        holes.push(hole);
+    }
+    Ok(holes)
+}
+
+/// Hash of a chunk of zeros, i.e. the content of a hole.
+pub fn zeros_hash(size: u64) -> Hash {
+    read_to_get_hash(io::repeat(0), Some(size)).expect("Unexpected: in-memory hashing failed")
+}
+
+/// Memoized [`zeros_hash`]. Hole chunks come in few sizes, the chunk size and the tails of
+/// files, and hashing a chunk of zeros takes as long as hashing any chunk.
+#[derive(Default)]
+pub struct ZerosHashes(HashMap<u64, Hash>);
+
+impl ZerosHashes {
+    pub fn get(&mut self, size: u64) -> Hash {
+        *self.0.entry(size).or_insert_with(|| zeros_hash(size))
+    }
+}
+
+fn may_be_sparse(file: &File) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = file.metadata()?;
+        Ok(metadata.blocks() * 512 < metadata.size())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        Ok(false)
+    }
+}
+
+/// Offset of the first data byte at or after `offset`; `None` if only a hole follows.
+fn next_data(file: &File, offset: u64) -> io::Result<Option<u64>> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos"
+        ))] {
+            use std::os::fd::AsRawFd;
+            let r = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+            if r >= 0 {
+                return Ok(Some(r as u64));
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                // no `SEEK_DATA` support in the file system
+                Some(libc::EINVAL) => Ok(Some(offset)),
+                _ => Err(e),
+            }
+        } else {
+            let _ = file;
+            // every byte counts as data
+            Ok(Some(offset))
+        }
+    }
+}
+
+/// Offset of the first hole byte at or after `offset`, which must lie in the file; the end
+/// of the file counts as a hole.
+fn next_hole(file: &File, offset: u64) -> io::Result<u64> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos"
+        ))] {
+            use std::os::fd::AsRawFd;
+            let r = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_HOLE) };
+            if r >= 0 {
+                return Ok(r as u64);
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // no `SEEK_HOLE` support, or the file shrank
+                Some(libc::EINVAL) | Some(libc::ENXIO) => Ok(u64::MAX),
+                _ => Err(e),
+            }
+        } else {
+            let _ = file;
+            Ok(u64::MAX)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(x: &[(u64, u64)]) -> Vec<Range> {
+        x.iter()
+            .map(|&(start, size)| Range { start, size })
+            .collect()
+    }
+
+    #[test]
+    fn extents_lie_between_holes() {
+        assert_eq!(data_extents(10, &[]), ranges(&[(0, 10)]));
+        assert_eq!(data_extents(10, &ranges(&[(0, 10)])), vec![]);
+        assert_eq!(
+            data_extents(10, &ranges(&[(0, 2), (5, 1)])),
+            ranges(&[(2, 3), (6, 4)])
+        );
+        assert_eq!(data_extents(10, &ranges(&[(3, 7)])), ranges(&[(0, 3)]));
+        assert_eq!(stored_size(10, &ranges(&[(0, 2), (5, 1)])), 7);
+    }
+
+    #[test]
+    fn holes_round_trip() {
+        let holes = ranges(&[(0, 2), (5, 1), (8, 2)]);
+        let data = encode_holes(&holes);
+        assert_eq!(data.len(), 3 * HOLE_ENCODED_SIZE);
+        assert_eq!(decode_holes(&data, 10).unwrap(), holes);
+        assert_eq!(decode_holes(&[], 10).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn bad_holes_are_rejected() {
+        for (holes, size) in [
+            // past the end of the chunk
+            (vec![(8, 3)], 10),
+            (vec![(2, u64::MAX)], 10),
+            // empty
+            (vec![(2, 0)], 10),
+            // unsorted, overlapping or adjacent
+            (vec![(5, 1), (0, 2)], 10),
+            (vec![(0, 3), (2, 2)], 10),
+            (vec![(0, 2), (2, 2)], 10),
+        ] {
+            let e = decode_holes(&encode_holes(&ranges(&holes)), size)
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{holes:?}");
+        }
+        let e = decode_holes(&[0; HOLE_ENCODED_SIZE - 1], 10).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn holes_are_found_inside_chunks() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        const MIB: u64 = 1024 * 1024;
+        let path =
+            std::env::temp_dir().join(format!("backup-tool-test-{}-sparse", std::process::id()));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(3 * MIB).unwrap();
+        file.seek(SeekFrom::Start(MIB)).unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        file.sync_all().unwrap();
+        let chunks = ranges(&[(0, 2 * MIB), (2 * MIB, 2 * MIB)]);
+        let holes = may_be_sparse(&file)
+            .unwrap()
+            .then(|| chunk_holes(&file, &chunks).unwrap());
+        drop(file);
+        let _ = std::fs::remove_file(&path);
+
+        // the file system can't store sparse files
+        let Some(holes) = holes else { return };
+        assert_eq!(
+            holes,
+            vec![
+                ranges(&[(0, MIB), (MIB + 64 * 1024, MIB - 64 * 1024)]),
+                // the range goes past the end of the file
+                ranges(&[(0, MIB)]),
+            ]
+        );
+    }
+}
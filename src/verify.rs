@@ -0,0 +1,91 @@
+//! Checking a backup set against its index.
+
+use crate::db::IndexDb;
+use crate::{BakInputReader, HashReadWrapper};
+use log::{info, warn};
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+#[derive(Default, Debug)]
+pub struct VerifyReport {
+    pub volume_count: usize,
+    pub chunk_count: u64,
+    /// Bytes of chunk data read and checked
+    pub byte_count: u64,
+    /// Human-readable descriptions of missing or damaged chunks
+    pub failures: Vec<String>,
+}
+
+/// Reads every chunk stored in `backup_dir` (an output directory holding `index.db` and
+/// 'bak' files) and checks it against the hash recorded in the index.
+pub fn verify(
+    backup_dir: &Path,
+    input_filter: Option<&Vec<OsString>>,
+) -> anyhow::Result<VerifyReport> {
+    let db = IndexDb::open_read_only(backup_dir.join("index.db"))?;
+    let mut chunks = db
+        .select_chunk_all()?
+        .into_iter()
+        // holes have no data to check
+        .filter(|x| x.stored_size() != 0)
+        .collect::<Vec<_>>();
+    chunks.sort_by_key(|x| (x.bak_n, x.offset));
+
+    let mut report = VerifyReport::default();
+    for volume_chunks in chunks.chunk_by(|a, b| a.bak_n == b.bak_n) {
+        let bak_n = volume_chunks[0].bak_n;
+        let bak_file = backup_dir.join(format!("bak{bak_n}"));
+        report.volume_count += 1;
+        if !bak_file.exists() {
+            report.failures.push(format!(
+                "Volume bak{bak_n} is missing ({} chunk(s))",
+                volume_chunks.len()
+            ));
+            continue;
+        }
+        info!("Verifying {}", bak_file.display());
+        let mut reader = BakInputReader::open(&bak_file, input_filter)?;
+        let mut position = 0_u64;
+        for (i, c) in volume_chunks.iter().enumerate() {
+            let result = reader.skip(c.offset - position).and_then(|_| {
+                let mut hash_reader =
+                    HashReadWrapper::new(io::Read::take(&mut reader, c.stored_size()));
+                let read = io::copy(&mut hash_reader, &mut io::sink())?;
+                Ok((read, hash_reader.finalize()))
+            });
+            let (read, hash) = match result {
+                Ok(x) => x,
+                Err(e) => {
+                    report.failures.push(format!(
+                        "bak{bak_n}: unreadable from offset {}: {e} ({} chunk(s) unchecked)",
+                        c.offset,
+                        volume_chunks.len() - i
+                    ));
+                    break;
+                }
+            };
+            report.chunk_count += 1;
+            report.byte_count += read;
+            if read != c.stored_size() {
+                report.failures.push(format!(
+                    "bak{bak_n}: truncated chunk at offset {}",
+                    c.offset
+                ));
+                break;
+            }
+            if *hash != c.chunk_hash {
+                report.failures.push(format!(
+                    "bak{bak_n}: corrupted chunk at offset {}",
+                    c.offset
+                ));
+            }
+            position = c.offset + c.stored_size();
+        }
+    }
+
+    for x in &report.failures {
+        warn!("{x}");
+    }
+    Ok(report)
+}
@@ -0,0 +1,471 @@
+//! On-disk layout of 'bak' volumes.
+//!
+//! A volume is a plain concatenation of chunk frames, before any output filter is applied:
+//!
+//! ```text
+//! | header | chunk data | chunk hash |
+//! ```
+//!
+//! The header carries enough information about the owning file for an index to be rebuilt
+//! from the volumes alone. All integers are little-endian.
+//!
+//! Header fields, in order: magic, `chunk_n` (u32), `chunk_count` (u32), `file_size` (u64),
+//! `mtime` (u64), `file_hash`, `chunk_size` (u64), the source root as its id (u32) and
+//! length-prefixed (u32) path, the length-prefixed (u32) file path, and last the holes of a
+//! sparse file in the chunk, prefixed by their count (u32) and each encoded as by
+//! [`encode_holes`]. Only the data between the holes follows, then its hash.
+//!
+//! Frames of the first version lack the root fields and the holes. Their chunks either have
+//! no hole (`BKF1`), or lie entirely in one (`BKH1`) and are written as a header alone. They
+//! are still read, as root 0.
+
+use crate::sparse::{decode_holes, encode_holes, stored_size, ZerosHashes, HOLE_ENCODED_SIZE};
+use crate::{read_to_get_hash, Hash, PathBytes, Range, HASH_SIZE};
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+pub const FRAME_MAGIC: [u8; 4] = *b"BKF2";
+/// Magics of frames written before roots and holes inside chunks were recorded
+const FRAME_MAGIC_V1: [u8; 4] = *b"BKF1";
+const HOLE_FRAME_MAGIC_V1: [u8; 4] = *b"BKH1";
+
+/// Longest path a header may carry, like `PATH_MAX` on Linux; a longer one means a damaged
+/// header, which mustn't make a reader allocate gigabytes.
+const MAX_PATH_LEN: u32 = 4096;
+/// Most holes a header may carry, for the same reason; a chunk of 8 GiB alternating data and
+/// holes of 4 KiB blocks has this many.
+const MAX_HOLE_COUNT: u32 = 1 << 20;
+
+/// magic + chunk_n + chunk_count + file_size + mtime + file_hash + chunk_size + path_len
+const HEADER_FIXED_SIZE: usize = 4 + 4 + 4 + 8 + 8 + HASH_SIZE + 8 + 4;
+/// root id + root path_len + hole count
+const HEADER_V2_SIZE: usize = 4 + 4 + 4;
+
+#[derive(Clone)]
+pub struct FrameHeader {
+    /// Relative path of the file this chunk belongs to
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub mtime: u64,
+    pub file_hash: Hash,
+    /// Index of this chunk in the file, starting from zero
+    pub chunk_n: u32,
+    pub chunk_count: u32,
+    /// Size of the chunk in the file, holes included
+    pub chunk_size: u64,
+    /// Holes in the chunk, relative to its start; they read as zeros and aren't stored
+    pub holes: Vec<Range>,
+    /// Id of the source root `path` is relative to
+    pub root: u32,
+    /// Canonical path of that root; `None` in frames of the first version, which are
+    /// written without root fields
+    pub root_path: Option<PathBuf>,
+}
+
+impl FrameHeader {
+    /// Size of the chunk data following the header.
+    pub fn stored_size(&self) -> u64 {
+        stored_size(self.chunk_size, &self.holes)
+    }
+
+    pub fn encoded_len(&self) -> u64 {
+        let v2_len = match &self.root_path {
+            Some(x) => {
+                HEADER_V2_SIZE + PathBytes::from(x).len() + self.holes.len() * HOLE_ENCODED_SIZE
+            }
+            None => 0,
+        };
+        (HEADER_FIXED_SIZE + v2_len + PathBytes::from(&self.path).len()) as u64
+    }
+
+    /// A hole frame of the first version: nothing follows the header.
+    fn is_v1_hole(&self) -> bool {
+        self.root_path.is_none() && !self.holes.is_empty()
+    }
+
+    /// Size of the whole frame: header, data and the trailing hash.
+    pub fn frame_len(&self) -> u64 {
+        if self.is_v1_hole() {
+            return self.encoded_len();
+        }
+        self.encoded_len() + self.stored_size() + HASH_SIZE as u64
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let path = PathBytes::from(&self.path);
+        let magic = match &self.root_path {
+            Some(_) => &FRAME_MAGIC,
+            None if self.holes.is_empty() => &FRAME_MAGIC_V1,
+            None if self.stored_size() == 0 && self.holes.len() == 1 => &HOLE_FRAME_MAGIC_V1,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Holes inside a chunk need a frame of the current version",
+                ))
+            }
+        };
+        writer.write_all(magic)?;
+        writer.write_all(&self.chunk_n.to_le_bytes())?;
+        writer.write_all(&self.chunk_count.to_le_bytes())?;
+        writer.write_all(&self.file_size.to_le_bytes())?;
+        writer.write_all(&self.mtime.to_le_bytes())?;
+        writer.write_all(&*self.file_hash)?;
+        writer.write_all(&self.chunk_size.to_le_bytes())?;
+        if let Some(root_path) = &self.root_path {
+            let root_path = PathBytes::from(root_path);
+            writer.write_all(&self.root.to_le_bytes())?;
+            writer.write_all(&(root_path.len() as u32).to_le_bytes())?;
+            writer.write_all(&root_path)?;
+        }
+        writer.write_all(&(path.len() as u32).to_le_bytes())?;
+        writer.write_all(&path)?;
+        if self.root_path.is_some() {
+            writer.write_all(&(self.holes.len() as u32).to_le_bytes())?;
+            writer.write_all(&encode_holes(&self.holes))?;
+        }
+        Ok(())
+    }
+
+    /// Returns `None` on a clean end of stream.
+    pub fn read_from(mut reader: impl Read) -> io::Result<Option<Self>> {
+        let mut magic = [0_u8; 4];
+        if !read_exact_or_eof(&mut reader, &mut magic)? {
+            return Ok(None);
+        }
+        if magic != FRAME_MAGIC && magic != FRAME_MAGIC_V1 && magic != HOLE_FRAME_MAGIC_V1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Bad frame magic",
+            ));
+        }
+        let chunk_n = u32::from_le_bytes(read_array(&mut reader)?);
+        let chunk_count = u32::from_le_bytes(read_array(&mut reader)?);
+        let file_size = u64::from_le_bytes(read_array(&mut reader)?);
+        let mtime = u64::from_le_bytes(read_array(&mut reader)?);
+        let file_hash = Hash(read_array(&mut reader)?);
+        let chunk_size = u64::from_le_bytes(read_array(&mut reader)?);
+        let (root, root_path) = if magic == FRAME_MAGIC {
+            let root = u32::from_le_bytes(read_array(&mut reader)?);
+            (root, Some(read_path(&mut reader)?))
+        } else {
+            (0, None)
+        };
+        let path = read_path(&mut reader)?;
+        let holes = match magic {
+            FRAME_MAGIC => read_holes(&mut reader, chunk_size)?,
+            HOLE_FRAME_MAGIC_V1 => vec![Range {
+                start: 0,
+                size: chunk_size,
+            }],
+            _ => Vec::new(),
+        };
+        Ok(Some(Self {
+            path,
+            file_size,
+            mtime,
+            file_hash,
+            chunk_n,
+            chunk_count,
+            chunk_size,
+            holes,
+            root,
+            root_path,
+        }))
+    }
+}
+
+/// Reads a path prefixed with its length as a `u32`.
+fn read_path(mut reader: impl Read) -> io::Result<PathBuf> {
+    let len = u32::from_le_bytes(read_array(&mut reader)?);
+    if len > MAX_PATH_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Path length {len} exceeds {MAX_PATH_LEN}"),
+        ));
+    }
+    let mut path = vec![0_u8; len as usize];
+    reader.read_exact(&mut path)?;
+    Ok(PathBytes(path).into_path_buf())
+}
+
+/// Reads the holes of a chunk of `chunk_size` bytes, prefixed with their count as a `u32`.
+fn read_holes(mut reader: impl Read, chunk_size: u64) -> io::Result<Vec<Range>> {
+    let count = u32::from_le_bytes(read_array(&mut reader)?);
+    if count > MAX_HOLE_COUNT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Hole count {count} exceeds {MAX_HOLE_COUNT}"),
+        ));
+    }
+    let mut data = vec![0_u8; count as usize * HOLE_ENCODED_SIZE];
+    reader.read_exact(&mut data)?;
+    decode_holes(&data, chunk_size)
+}
+
+/// A frame as found while scanning a volume.
+pub struct ScannedFrame {
+    pub header: FrameHeader,
+    /// Offset of the chunk data in the volume
+    pub offset: u64,
+    /// Hash stored in the frame trailer
+    pub stored_hash: Hash,
+    /// Hash computed from the chunk data actually read
+    pub actual_hash: Hash,
+}
+
+/// Sequentially reads frames from an (unfiltered) volume stream.
+pub struct VolumeScanner<R: Read> {
+    inner: R,
+    position: u64,
+    zeros_hashes: ZerosHashes,
+}
+
+impl<R: Read> VolumeScanner<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            inner: reader,
+            position: 0,
+            zeros_hashes: ZerosHashes::default(),
+        }
+    }
+
+    /// Current offset in the volume; points to the next frame after a successful read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn next_frame(&mut self) -> io::Result<Option<ScannedFrame>> {
+        let Some(header) = FrameHeader::read_from(&mut self.inner)? else {
+            return Ok(None);
+        };
+        let offset = self.position + header.encoded_len();
+        if header.is_v1_hole() {
+            // recorded as the hash of the zeros it reads as
+            let hash = self.zeros_hashes.get(header.chunk_size);
+            self.position += header.frame_len();
+            return Ok(Some(ScannedFrame {
+                header,
+                offset,
+                stored_hash: hash,
+                actual_hash: hash,
+            }));
+        }
+        let mut data = (&mut self.inner).take(header.stored_size());
+        let actual_hash = read_to_get_hash(&mut data, None)?;
+        if data.limit() != 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let stored_hash = Hash(read_array(&mut self.inner)?);
+        self.position += header.frame_len();
+        Ok(Some(ScannedFrame {
+            header,
+            offset,
+            stored_hash,
+            actual_hash,
+        }))
+    }
+}
+
+fn read_array<const N: usize>(mut reader: impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0_u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Like [`Read::read_exact`], but returns `false` if the stream ends before any byte is read.
+fn read_exact_or_eof(mut reader: impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse::zeros_hash;
+    use std::path::Path;
+
+    fn header(path: &str, chunk_n: u32, chunk_size: u64, holes: &[(u64, u64)]) -> FrameHeader {
+        FrameHeader {
+            path: path.into(),
+            file_size: 3 * chunk_size,
+            mtime: 1_700_000_000_123_456_789,
+            file_hash: Hash([7; HASH_SIZE]),
+            chunk_n,
+            chunk_count: 3,
+            chunk_size,
+            holes: holes
+                .iter()
+                .map(|&(start, size)| Range { start, size })
+                .collect(),
+            root: 1,
+            root_path: Some("/srv/data".into()),
+        }
+    }
+
+    /// Appends a frame as a backup writes it: header, then data and hash unless a hole of
+    /// the first version.
+    fn write_frame(volume: &mut Vec<u8>, header: &FrameHeader, data: &[u8]) {
+        header.write_to(&mut *volume).unwrap();
+        if !header.is_v1_hole() {
+            volume.extend_from_slice(data);
+            volume.extend_from_slice(&*read_to_get_hash(data, None).unwrap());
+        }
+    }
+
+    fn sample_volume() -> (Vec<u8>, Vec<FrameHeader>, Vec<&'static [u8]>) {
+        let headers = vec![
+            header("dir/a.bin", 0, 5, &[]),
+            header("dir/a.bin", 1, 5, &[(0, 5)]),
+            header("dir/a.bin", 2, 5, &[(0, 1), (3, 1)]),
+        ];
+        let data: Vec<&[u8]> = vec![b"hello", b"", b"orl"];
+        let mut volume = Vec::new();
+        for (x, data) in headers.iter().zip(&data) {
+            write_frame(&mut volume, x, data);
+        }
+        (volume, headers, data)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (volume, headers, data) = sample_volume();
+        let mut scanner = VolumeScanner::new(&volume[..]);
+        let mut position = 0;
+        for (expected, data) in headers.iter().zip(data) {
+            let frame = scanner.next_frame().unwrap().unwrap();
+            let x = &frame.header;
+            assert_eq!(x.path, expected.path);
+            assert_eq!(x.file_size, expected.file_size);
+            assert_eq!(x.mtime, expected.mtime);
+            assert_eq!(x.file_hash, expected.file_hash);
+            assert_eq!((x.chunk_n, x.chunk_count), (expected.chunk_n, 3));
+            assert_eq!((x.chunk_size, &x.holes), (5, &expected.holes));
+            assert_eq!(x.stored_size(), data.len() as u64);
+            assert_eq!((x.root, &x.root_path), (1, &expected.root_path));
+            assert_eq!(frame.offset, position + expected.encoded_len());
+            assert_eq!(frame.stored_hash, frame.actual_hash);
+            assert_eq!(frame.actual_hash, read_to_get_hash(data, None).unwrap());
+            position += expected.frame_len();
+            assert_eq!(scanner.position(), position);
+        }
+        assert_eq!(position, volume.len() as u64);
+        assert!(scanner.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn first_version_frames_are_read_as_root_0() {
+        let mut volume = Vec::new();
+        let mut old = header("a.bin", 0, 5, &[]);
+        (old.root, old.root_path) = (0, None);
+        write_frame(&mut volume, &old, b"hello");
+        let mut old_hole = header("a.bin", 1, 5, &[(0, 5)]);
+        (old_hole.root, old_hole.root_path) = (0, None);
+        write_frame(&mut volume, &old_hole, &[]);
+        assert_eq!(&volume[..4], b"BKF1");
+        assert_eq!(&volume[old.frame_len() as usize..][..4], b"BKH1");
+
+        let mut scanner = VolumeScanner::new(&volume[..]);
+        for expected in [&old, &old_hole] {
+            let frame = scanner.next_frame().unwrap().unwrap();
+            assert_eq!(frame.header.path, Path::new("a.bin"));
+            assert_eq!((frame.header.root, frame.header.root_path), (0, None));
+            assert_eq!(frame.header.holes, expected.holes);
+            assert_eq!(frame.stored_hash, frame.actual_hash);
+        }
+        assert_eq!(scanner.position(), volume.len() as u64);
+        // a hole reads as the hash of its zeros
+        let frame = VolumeScanner::new(&volume[old.frame_len() as usize..])
+            .next_frame()
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.actual_hash, zeros_hash(5));
+
+        // holes inside a chunk can't be written in the first version
+        let mut partial = header("a.bin", 0, 5, &[(1, 2)]);
+        partial.root_path = None;
+        let e = partial.write_to(io::sink()).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn corrupted_data_is_detected() {
+        let (mut volume, headers, _) = sample_volume();
+        volume[headers[0].encoded_len() as usize] ^= 0xff;
+        let frame = VolumeScanner::new(&volume[..])
+            .next_frame()
+            .unwrap()
+            .unwrap();
+        assert_ne!(frame.stored_hash, frame.actual_hash);
+    }
+
+    #[test]
+    fn truncated_frames_fail() {
+        let (volume, headers, _) = sample_volume();
+        let first_len = headers[0].frame_len() as usize;
+        // cut in the header, the data and the trailing hash of the first frame
+        for end in [2, 20, first_len - HASH_SIZE + 2, first_len - 1] {
+            let e = VolumeScanner::new(&volume[..end])
+                .next_frame()
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof, "cut at {end}");
+        }
+        // a clean end between frames
+        let mut scanner = VolumeScanner::new(&volume[..first_len]);
+        assert!(scanner.next_frame().unwrap().is_some());
+        assert!(scanner.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_magic_fails() {
+        let (mut volume, _, _) = sample_volume();
+        volume[0] = b'X';
+        let e = VolumeScanner::new(&volume[..]).next_frame().err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_path_len_fails() {
+        let (volume, headers, _) = sample_volume();
+        // the root path_len follows the root id where the fixed part has the path_len; the
+        // path_len sits right before the path, followed by the hole count
+        let root_at = HEADER_FIXED_SIZE;
+        let at = headers[0].encoded_len() as usize - headers[0].path.as_os_str().len() - 4 - 4;
+        for at in [root_at, at] {
+            let mut volume = volume.clone();
+            volume[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let e = VolumeScanner::new(&volume[..]).next_frame().err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn bad_holes_fail() {
+        let (volume, headers, _) = sample_volume();
+        // the hole count ends the header of a chunk without holes
+        let at = headers[0].encoded_len() as usize - 4;
+        let mut oversized = volume.clone();
+        oversized[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        // the single hole of the second frame, made to end past its chunk
+        let at = (headers[0].frame_len() + headers[1].encoded_len()) as usize - 8;
+        let mut outside = volume.clone();
+        outside[at..at + 8].copy_from_slice(&6_u64.to_le_bytes());
+        for volume in [oversized, outside] {
+            let mut scanner = VolumeScanner::new(&volume[..]);
+            let e = std::iter::from_fn(|| scanner.next_frame().transpose())
+                .find_map(Result::err)
+                .unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Extended attributes.
+//!
+//! On Linux, POSIX ACLs are kept in the `system.posix_acl_access` and
+//! `system.posix_acl_default` attributes, so capturing all attributes captures ACLs as well.
+
+use crate::{read_to_get_hash, Hash, PathBytes};
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+/// Extended attributes of a file, sorted by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Xattrs(pub Vec<(OsString, Vec<u8>)>);
+
+impl Xattrs {
+    /// Reads all attributes of `path`. `follow` makes symlinks be resolved.
+    ///
+    /// A file system without extended attribute support gives an empty set.
+    pub fn read(path: impl AsRef<Path>, follow: bool) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            let path = path.as_ref();
+            let names = match if follow {
+                xattr::list_deref(path)
+            } else {
+                xattr::list(path)
+            } {
+                Ok(x) => x,
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Self::default()),
+                Err(e) => return Err(e),
+            };
+            let mut attrs = Vec::new();
+            for name in names {
+                let value = if follow {
+                    xattr::get_deref(path, &name)?
+                } else {
+                    xattr::get(path, &name)?
+                };
+                // may be removed in between
+                if let Some(v) = value {
+                    attrs.push((name, v));
+                }
+            }
+            attrs.sort();
+            Ok(Self(attrs))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (path, follow);
+            Ok(Self::default())
+        }
+    }
+
+    /// Sets all attributes on `path`, without following symlinks. One failing doesn't stop
+    /// the others; the first error is returned.
+    pub fn apply(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let mut result = Ok(());
+            for (name, value) in &self.0 {
+                let r = xattr::set(path.as_ref(), name, value);
+                if result.is_ok() {
+                    result = r;
+                }
+            }
+            result?;
+        }
+        #[cfg(not(unix))]
+        if !self.0.is_empty() {
+            let _ = path;
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        Ok(())
+    }
+
+    /// Layout: `(name length: u32, name, value length: u32, value)*`, little-endian.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (name, value) in &self.0 {
+            let name = PathBytes::from(name);
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            data.extend_from_slice(&name);
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    pub fn decode(mut data: &[u8]) -> io::Result<Self> {
+        let take = |data: &mut &[u8]| -> io::Result<Vec<u8>> {
+            let len_bytes = data.get(..4).ok_or(io::ErrorKind::InvalidData)?;
+            let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+            let bytes = data.get(4..4 + len).ok_or(io::ErrorKind::InvalidData)?;
+            *data = &data[4 + len..];
+            Ok(bytes.to_vec())
+        };
+        let mut attrs = Vec::new();
+        while !data.is_empty() {
+            let name = take(&mut data)?;
+            let value = take(&mut data)?;
+            let name = PathBytes(name).into_path_buf().into_os_string();
+            attrs.push((name, value));
+        }
+        Ok(Self(attrs))
+    }
+
+    pub fn hash(&self) -> Hash {
+        read_to_get_hash(&self.encode()[..], None).expect("Unexpected: in-memory hashing failed")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}